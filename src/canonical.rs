@@ -0,0 +1,36 @@
+use tan::expr::Expr;
+
+use crate::{
+    compact::CompactFormatter,
+    types::{MapKeyOrder, Options},
+};
+
+/// The CanonicalFormatter renders expressions in a canonical form, equivalent
+/// expressions always format identically. Comments are removed, `Map` keys
+/// are sorted, numbers and strings are normalized and the layout is fixed,
+/// independent of the width settings. The canonical form is useful for
+/// hashing, deduplication and semantic comparison of Tan sources.
+pub struct CanonicalFormatter<'a> {
+    exprs: &'a [Expr],
+}
+
+impl<'a> CanonicalFormatter<'a> {
+    pub fn new(exprs: &'a [Expr]) -> Self {
+        Self { exprs }
+    }
+
+    /// Formats expressions into the canonical form.
+    pub fn format(self) -> String {
+        // #insight Numbers and strings are normalized by the Arranger, they
+        // are rendered from the parsed values, not the source text.
+        let options = Options {
+            strip_comments: true,
+            map_key_order: MapKeyOrder::Alphabetical,
+            ..Default::default()
+        };
+
+        CompactFormatter::new(self.exprs)
+            .with_options(options)
+            .format()
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{HashSet, VecDeque};
+
+use tan::{
+    api::lex_string,
+    expr::Expr,
+    lexer::token::{Token, TokenKind},
+};
+
+use crate::util::display_width;
+
+// #insight
+// The parser does not retain every comment as an `Expr::Comment`, depending
+// on the position of the comment. The token stream contains all comments, the
+// comments that are missing from the expressions (orphans) are reconciled
+// with the expressions by source position: an orphan is emitted before the
+// next expression (leading), after the previous expression on the same line
+// (trailing), or before the end of the enclosing list (dangling).
+
+/// A comment from the token stream that is missing from the expressions.
+#[derive(Clone, Debug)]
+pub(crate) struct Comment {
+    pub text: String,
+    /// The source index of the start of the comment.
+    pub index: usize,
+    /// The source line of the comment.
+    pub line: usize,
+}
+
+fn comment_from_token(token: &Token) -> Option<Comment> {
+    let TokenKind::Comment(text, ..) = token.kind() else {
+        return None;
+    };
+
+    let range = token.range();
+
+    Some(Comment {
+        text: text.clone(),
+        index: range.start.index,
+        line: range.start.line,
+    })
+}
+
+/// Returns the comments of the source that are missing from the expressions,
+/// ordered by source position.
+pub(crate) fn orphan_comments(input: &str, exprs: &[Expr]) -> VecDeque<Comment> {
+    // #insight If the source cannot be tokenized, the expressions are not
+    // parsed from this source.
+    let Ok(tokens) = lex_string(input) else {
+        return VecDeque::new();
+    };
+
+    // Collect the positions of the comments retained in the expressions.
+    let mut retained = HashSet::new();
+    let mut stack: Vec<&Expr> = exprs.iter().collect();
+    while let Some(expr) = stack.pop() {
+        match expr.unpack() {
+            Expr::Comment(..) => {
+                if let Some(range) = expr.range() {
+                    retained.insert(range.start.index);
+                }
+            }
+            Expr::List(exprs) => stack.extend(exprs),
+            _ => (),
+        }
+    }
+
+    tokens
+        .iter()
+        .filter_map(comment_from_token)
+        .filter(|comment| !retained.contains(&comment.index))
+        .collect()
+}
+
+/// Returns true if the comment text is a list item, e.g. `- item` or `1. item`.
+fn is_list_item(text: &str) -> bool {
+    if text.starts_with("- ") || text.starts_with("* ") || text.starts_with("+ ") {
+        return true;
+    }
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(0);
+    digits > 0 && (text[digits..].starts_with(". ") || text[digits..].starts_with(") "))
+}
+
+/// Returns true if the comment text starts with a tag, e.g. `#todo`.
+fn is_tag(text: &str) -> bool {
+    text.strip_prefix('#')
+        .is_some_and(|tag| tag.starts_with(|c: char| c.is_alphabetic()))
+}
+
+/// A paragraph of consecutive comment lines, to be reflowed.
+struct Paragraph<'a> {
+    marker: &'a str,
+    lines: Vec<&'a str>,
+    words: Vec<&'a str>,
+}
+
+impl Paragraph<'_> {
+    fn fill(self, width: usize, output: &mut Vec<String>) {
+        // #insight A single line that fits is kept intact.
+        if let [line] = self.lines[..] {
+            if display_width(line) <= width {
+                output.push(line.to_string());
+                return;
+            }
+        }
+
+        let mut line = self.marker.to_string();
+        let mut line_width = display_width(self.marker);
+        for word in self.words {
+            let word_width = display_width(word);
+            if line.len() > self.marker.len() && line_width + 1 + word_width > width {
+                output.push(std::mem::replace(&mut line, self.marker.to_string()));
+                line_width = display_width(self.marker);
+            }
+            line.push(' ');
+            line.push_str(word);
+            line_width += 1 + word_width;
+        }
+        if line.len() > self.marker.len() {
+            output.push(line);
+        }
+    }
+}
+
+/// Reflows a block of consecutive full-line comments into paragraphs that fit
+/// within the width. Empty comment lines, code examples (indented or fenced
+/// lines), list items and tag lines (e.g. `#todo`) are kept intact. Words
+/// longer than the width are not split.
+pub(crate) fn reflow_comment(block: &str, width: usize) -> Vec<String> {
+    let mut output = Vec::new();
+    let mut paragraph: Option<Paragraph> = None;
+    let mut in_fence = false;
+
+    for line in block.lines() {
+        let line = line.trim_end();
+        let (marker, content) = line.split_at(line.find(|c| c != ';').unwrap_or(line.len()));
+        let text = content.strip_prefix(' ').unwrap_or(content);
+
+        let is_fence = text.trim_start().starts_with("```");
+        let is_verbatim = in_fence
+            || is_fence
+            || text.is_empty()
+            || text.starts_with(char::is_whitespace)
+            || is_list_item(text)
+            || is_tag(text);
+        if is_fence {
+            in_fence = !in_fence;
+        }
+
+        let is_continuation = paragraph
+            .as_ref()
+            .is_some_and(|p| p.marker == marker && !is_verbatim);
+        if !is_continuation {
+            if let Some(paragraph) = paragraph.take() {
+                paragraph.fill(width, &mut output);
+            }
+        }
+
+        if is_verbatim {
+            output.push(line.to_string());
+            continue;
+        }
+
+        let paragraph = paragraph.get_or_insert_with(|| Paragraph {
+            marker,
+            lines: Vec::new(),
+            words: Vec::new(),
+        });
+        paragraph.lines.push(line);
+        paragraph.words.extend(text.split_whitespace());
+    }
+
+    if let Some(paragraph) = paragraph {
+        paragraph.fill(width, &mut output);
+    }
+
+    output
+}
+
+/// Normalizes a comment: replaces the marker, ensures a single space after
+/// the marker and trims trailing whitespace. An indentation of 4 or more
+/// (e.g. a code example) is retained.
+pub(crate) fn normalize_comment(text: &str, marker: &str) -> String {
+    let content = text.trim_start_matches(';').trim_end();
+    let content = content.strip_prefix(' ').unwrap_or(content);
+
+    let indent = content.len() - content.trim_start().len();
+    let content = if indent < 4 {
+        content.trim_start()
+    } else {
+        content
+    };
+
+    if content.is_empty() {
+        marker.to_string()
+    } else {
+        format!("{marker} {content}")
+    }
+}
@@ -0,0 +1,122 @@
+use tan::expr::Expr;
+
+use crate::{
+    layout::{Arranger, Event, Layout},
+    types::{Dialect, Options},
+    util::{format_annotations, is_comment},
+};
+
+/// The CompactFormatter renders the Layout model with the minimum whitespace
+/// needed to reparse the output identically. Top-level expressions are
+/// separated with newlines, there is no indentation.
+pub struct CompactFormatter<'a> {
+    exprs: &'a [Expr],
+    pub dialect: Dialect,
+    options: Options,
+    output: String,
+    // A space is needed before the next fragment.
+    space_pending: bool,
+    // A newline is needed before the next fragment, e.g. after a comment.
+    newline_pending: bool,
+}
+
+impl<'a> CompactFormatter<'a> {
+    pub fn new(exprs: &'a [Expr]) -> Self {
+        Self::for_dialect(exprs, Dialect::default())
+    }
+
+    pub fn for_dialect(exprs: &'a [Expr], dialect: Dialect) -> Self {
+        Self {
+            exprs,
+            dialect,
+            options: Options::for_dialect(dialect),
+            output: String::new(),
+            space_pending: false,
+            newline_pending: false,
+        }
+    }
+
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Strips comments and doc annotations from the output.
+    pub fn strip_comments(mut self) -> Self {
+        self.options.strip_comments = true;
+        self
+    }
+
+    fn write_fragment(&mut self, fragment: &str) {
+        if fragment.trim().is_empty() {
+            // #insight Whitespace items (e.g. `Layout::space()`) are just separators.
+            self.space_pending = self.space_pending || !fragment.is_empty();
+            return;
+        }
+
+        if self.newline_pending {
+            self.output.push('\n');
+        } else if self.space_pending
+            && !self.output.is_empty()
+            && !self.output.ends_with(['(', '[', '{', '\n'])
+            && !fragment.starts_with([')', ']', '}'])
+        {
+            self.output.push(' ');
+        }
+
+        self.output.push_str(fragment);
+
+        self.space_pending = false;
+        // #insight A comment extends to the end of the line.
+        self.newline_pending = is_comment(fragment);
+    }
+
+    fn format_layout(&mut self, layout: &Layout) {
+        let mut events = layout.events();
+
+        while let Some(event) = events.next() {
+            match event {
+                Event::Text(s) => self.write_fragment(s),
+                Event::Separator(separator) => {
+                    self.space_pending = self.space_pending || !separator.is_empty();
+                }
+                Event::Ann(ann) => {
+                    let ann = format_annotations(ann);
+                    self.write_fragment(ann.trim_end());
+                    self.space_pending = true;
+                }
+                // #insight The compact form is always flat.
+                Event::Fit(flat, _) => events.push(flat),
+                // #insight The padding of a table is collapsed anyway.
+                Event::Table(table, _) => events.push(table),
+                Event::IndentStart(..)
+                | Event::IndentEnd(..)
+                | Event::Mark
+                | Event::HangStart(..)
+                | Event::HangEnd
+                | Event::LineStart => (),
+            }
+        }
+    }
+
+    /// Formats expressions into a compact (minified) form.
+    pub fn format(mut self) -> String {
+        let layout = Arranger::new(self.exprs, self.dialect)
+            .with_options(self.options.clone())
+            .arrange();
+
+        // #insight The top-level expressions are separated with newlines.
+        if let Layout::Stack(rows) = &layout {
+            for row in rows {
+                if !self.output.is_empty() {
+                    self.newline_pending = true;
+                }
+                self.format_layout(row);
+            }
+        } else {
+            self.format_layout(&layout);
+        }
+
+        self.output
+    }
+}
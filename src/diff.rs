@@ -0,0 +1,391 @@
+use std::{
+    fmt::Write,
+    ops::{Index, IndexMut, Range},
+};
+
+// #todo consider a patience diff, it produces more readable hunks for code.
+// #todo consider word-level highlighting within changed lines.
+
+/// The number of unchanged lines shown around each change.
+const DEFAULT_CONTEXT_SIZE: usize = 3;
+
+const STYLE_BOLD: &str = "\x1b[1m";
+const STYLE_RED: &str = "\x1b[31m";
+const STYLE_GREEN: &str = "\x1b[32m";
+const STYLE_CYAN: &str = "\x1b[36m";
+const STYLE_RESET: &str = "\x1b[0m";
+
+/// A line-level edit, references lines by index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+impl Edit {
+    fn is_change(&self) -> bool {
+        !matches!(self, Edit::Equal(..))
+    }
+}
+
+/// A furthest reaching path vector, indexed by diagonal.
+struct Diagonals {
+    offset: isize,
+    v: Vec<usize>,
+}
+
+impl Diagonals {
+    fn new(max: usize) -> Self {
+        Self {
+            offset: max as isize,
+            v: vec![0; 2 * max + 1],
+        }
+    }
+}
+
+impl Index<isize> for Diagonals {
+    type Output = usize;
+
+    fn index(&self, k: isize) -> &usize {
+        &self.v[(k + self.offset) as usize]
+    }
+}
+
+impl IndexMut<isize> for Diagonals {
+    fn index_mut(&mut self, k: isize) -> &mut usize {
+        &mut self.v[(k + self.offset) as usize]
+    }
+}
+
+/// Finds the start of the middle snake of an optimal edit path between the
+/// old and the new lines, by searching forward and backward simultaneously.
+/// The lines should differ at both ends.
+fn middle_snake(
+    old: &[&str],
+    new: &[&str],
+    vf: &mut Diagonals,
+    vb: &mut Diagonals,
+) -> Option<(usize, usize)> {
+    let n = old.len();
+    let m = new.len();
+    let delta = n as isize - m as isize;
+    let odd = delta & 1 == 1;
+
+    vf[1] = 0;
+    vb[1] = 0;
+
+    let d_max = (n + m).div_ceil(2) as isize;
+
+    for d in 0..=d_max {
+        // Forward pass.
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && vf[k - 1] < vf[k + 1]) {
+                vf[k + 1]
+            } else {
+                vf[k - 1] + 1
+            };
+            let mut y = (x as isize - k) as usize;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && old[x] == new[y] {
+                x += 1;
+                y += 1;
+            }
+            vf[k] = x;
+            if odd && (k - delta).abs() < d && vf[k] + vb[delta - k] >= n {
+                return Some((x0, y0));
+            }
+        }
+
+        // Backward pass, on the reversed lines.
+        for k in (-d..=d).step_by(2) {
+            let mut x = if k == -d || (k != d && vb[k - 1] < vb[k + 1]) {
+                vb[k + 1]
+            } else {
+                vb[k - 1] + 1
+            };
+            let mut y = (x as isize - k) as usize;
+            while x < n && y < m && old[n - x - 1] == new[m - y - 1] {
+                x += 1;
+                y += 1;
+            }
+            vb[k] = x;
+            if !odd && (k - delta).abs() <= d && vb[k] + vf[delta - k] >= n {
+                return Some((n - x, m - y));
+            }
+        }
+    }
+
+    None
+}
+
+/// Appends the edits between `old[old_range]` and `new[new_range]`, dividing
+/// the ranges at the middle snake.
+fn diff_ranges(
+    old: &[&str],
+    new: &[&str],
+    mut old_range: Range<usize>,
+    mut new_range: Range<usize>,
+    vf: &mut Diagonals,
+    vb: &mut Diagonals,
+    edits: &mut Vec<Edit>,
+) {
+    while !old_range.is_empty()
+        && !new_range.is_empty()
+        && old[old_range.start] == new[new_range.start]
+    {
+        edits.push(Edit::Equal(old_range.start, new_range.start));
+        old_range.start += 1;
+        new_range.start += 1;
+    }
+
+    let mut suffix_len = 0;
+    while !old_range.is_empty()
+        && !new_range.is_empty()
+        && old[old_range.end - 1] == new[new_range.end - 1]
+    {
+        old_range.end -= 1;
+        new_range.end -= 1;
+        suffix_len += 1;
+    }
+
+    let snake = if old_range.is_empty() || new_range.is_empty() {
+        None
+    } else {
+        middle_snake(&old[old_range.clone()], &new[new_range.clone()], vf, vb)
+    };
+
+    if let Some((x, y)) = snake {
+        let (x, y) = (old_range.start + x, new_range.start + y);
+        diff_ranges(
+            old,
+            new,
+            old_range.start..x,
+            new_range.start..y,
+            vf,
+            vb,
+            edits,
+        );
+        diff_ranges(old, new, x..old_range.end, y..new_range.end, vf, vb, edits);
+    } else {
+        edits.extend(old_range.clone().map(Edit::Delete));
+        edits.extend(new_range.clone().map(Edit::Insert));
+    }
+
+    for i in 0..suffix_len {
+        edits.push(Edit::Equal(old_range.end + i, new_range.end + i));
+    }
+}
+
+// #insight The linear space variant of Myers' algorithm is used, a large
+// rewrite would otherwise need memory quadratic in the number of edits.
+
+/// Computes a minimal line-level edit script, using Myers' algorithm.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let max = (old.len() + new.len()).div_ceil(2) + 1;
+    let mut vf = Diagonals::new(max);
+    let mut vb = Diagonals::new(max);
+
+    let mut edits = Vec::with_capacity(old.len() + new.len());
+    diff_ranges(
+        old,
+        new,
+        0..old.len(),
+        0..new.len(),
+        &mut vf,
+        &mut vb,
+        &mut edits,
+    );
+
+    // #insight Within a run of changes, deletions are listed first.
+    for run in edits.split_mut(|edit| !edit.is_change()) {
+        run.sort_by_key(|edit| matches!(edit, Edit::Insert(..)));
+    }
+
+    edits
+}
+
+/// Groups the edits into hunks (ranges of edit indices), each change is
+/// surrounded by up to `context_size` unchanged lines.
+fn hunks(edits: &[Edit], context_size: usize) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+
+    for (i, edit) in edits.iter().enumerate() {
+        if !edit.is_change() {
+            continue;
+        }
+
+        let start = i.saturating_sub(context_size);
+        let end = (i + context_size + 1).min(edits.len());
+
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    hunks
+}
+
+fn push_line(output: &mut String, marker: char, line: &str, style: Option<&str>) {
+    if let Some(style) = style {
+        output.push_str(style);
+    }
+    output.push(marker);
+    output.push_str(line.strip_suffix('\n').unwrap_or(line));
+    if style.is_some() {
+        output.push_str(STYLE_RESET);
+    }
+    output.push('\n');
+    if !line.ends_with('\n') {
+        output.push_str("\\ No newline at end of file\n");
+    }
+}
+
+/// Renders a unified diff between the original source and the formatted
+/// output. Returns `None` if the formatter would not change the source.
+/// The `path` is used in the file headers, `color` enables ANSI colors for
+/// terminal output.
+pub fn unified_diff(original: &str, formatted: &str, path: &str, color: bool) -> Option<String> {
+    if original == formatted {
+        return None;
+    }
+
+    // #insight Lines retain their terminator, so that a missing newline at the
+    // end of the file is detected as a change.
+    let old: Vec<&str> = original.split_inclusive('\n').collect();
+    let new: Vec<&str> = formatted.split_inclusive('\n').collect();
+
+    let edits = diff_lines(&old, &new);
+
+    let style = |s: &'static str| if color { Some(s) } else { None };
+
+    let mut output = String::new();
+
+    if let Some(bold) = style(STYLE_BOLD) {
+        output.push_str(bold);
+    }
+    let _ = write!(output, "--- a/{path}\n+++ b/{path}");
+    if color {
+        output.push_str(STYLE_RESET);
+    }
+    output.push('\n');
+
+    // The old/new line positions before each edit.
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for edit in &edits {
+        positions.push((old_pos, new_pos));
+        match edit {
+            Edit::Equal(..) => {
+                old_pos += 1;
+                new_pos += 1;
+            }
+            Edit::Delete(..) => old_pos += 1,
+            Edit::Insert(..) => new_pos += 1,
+        }
+    }
+    positions.push((old_pos, new_pos));
+
+    for (start, end) in hunks(&edits, DEFAULT_CONTEXT_SIZE) {
+        let (old_start, new_start) = positions[start];
+        let (old_end, new_end) = positions[end];
+        let old_len = old_end - old_start;
+        let new_len = new_end - new_start;
+
+        // #insight By convention, an empty range references the line before.
+        let old_start = if old_len > 0 {
+            old_start + 1
+        } else {
+            old_start
+        };
+        let new_start = if new_len > 0 {
+            new_start + 1
+        } else {
+            new_start
+        };
+
+        if let Some(cyan) = style(STYLE_CYAN) {
+            output.push_str(cyan);
+        }
+        let _ = write!(
+            output,
+            "@@ -{old_start},{old_len} +{new_start},{new_len} @@"
+        );
+        if color {
+            output.push_str(STYLE_RESET);
+        }
+        output.push('\n');
+
+        for edit in &edits[start..end] {
+            match *edit {
+                Edit::Equal(i, _) => push_line(&mut output, ' ', old[i], None),
+                Edit::Delete(i) => push_line(&mut output, '-', old[i], style(STYLE_RED)),
+                Edit::Insert(j) => push_line(&mut output, '+', new[j], style(STYLE_GREEN)),
+            }
+        }
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{diff_lines, Edit};
+
+    #[test]
+    fn diff_lines_works() {
+        let old = ["a", "b", "c"];
+        let new = ["a", "c", "d"];
+        let edits = diff_lines(&old, &new);
+
+        assert_eq!(
+            edits,
+            vec![
+                Edit::Equal(0, 0),
+                Edit::Delete(1),
+                Edit::Equal(2, 1),
+                Edit::Insert(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_lines_handles_large_rewrites() {
+        let old: Vec<String> = (0..8000).map(|i| format!("(old {i})")).collect();
+        let new: Vec<String> = (0..8000).map(|i| format!("(new {i})")).collect();
+        let old: Vec<&str> = old.iter().map(String::as_str).collect();
+        let new: Vec<&str> = new.iter().map(String::as_str).collect();
+
+        let edits = diff_lines(&old, &new);
+
+        assert_eq!(edits.len(), 16000);
+        assert!(edits.iter().all(Edit::is_change));
+    }
+
+    #[test]
+    fn diff_lines_finds_minimal_edits() {
+        let old = ["a", "b", "c", "a", "b", "b", "a"];
+        let new = ["c", "b", "a", "b", "a", "c"];
+        let edits = diff_lines(&old, &new);
+
+        let changes = edits.iter().filter(|edit| edit.is_change()).count();
+        assert_eq!(changes, 5);
+        let old_lines: Vec<&str> = edits
+            .iter()
+            .filter_map(|edit| match *edit {
+                Edit::Equal(i, _) | Edit::Delete(i) => Some(old[i]),
+                Edit::Insert(..) => None,
+            })
+            .collect();
+        let new_lines: Vec<&str> = edits
+            .iter()
+            .filter_map(|edit| match *edit {
+                Edit::Equal(_, j) | Edit::Insert(j) => Some(new[j]),
+                Edit::Delete(..) => None,
+            })
+            .collect();
+        assert_eq!(old_lines, old);
+        assert_eq!(new_lines, new);
+    }
+}
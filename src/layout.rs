@@ -1,14 +1,30 @@
-use std::collections::HashMap;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+};
 
 use tan::{
     expr::Expr,
     util::{fmt::format_float, put_back_iterator::PutBackIterator},
 };
+use unicode_segmentation::UnicodeSegmentation;
 
-use crate::{types::Dialect, util::escape_string};
+use crate::{
+    comments::{normalize_comment, Comment},
+    types::{Dialect, MapKeyOrder, Options},
+    util::{display_width, escape_string, format_annotations, is_comment, is_doc_annotation},
+};
 
 // #todo use source-code annotations to control formatting
 
+/// The maximum nesting depth of lists arranged with the full rules, deeper
+/// lists are formatted on a single line, without recursion.
+const MAX_ARRANGE_DEPTH: usize = 128;
+
+/// An annotation that requests sorting of the keys of the annotated Map.
+const SORT_KEYS_ANNOTATION: &str = "#sort-keys";
+
 // #todo add some explanation about the design, e.g. what does Layout do.
 
 // #todo somehow extract the force_vertical computation to include all parameters.
@@ -33,6 +49,21 @@ pub enum Layout {
     Item(String),
     Ann(HashMap<String, Expr>, Box<Layout>),
     Separator,
+    /// The flat (single-line) arrangement if it fits within the line size,
+    /// otherwise the broken (multi-line) arrangement. The choice is made when
+    /// rendering, where the column is known.
+    Fit(Box<Layout>, Box<Layout>),
+    /// The head, followed by children aligned with the column of the head
+    /// start plus the offset (hanging alignment). Falls back to indentation
+    /// if the children do not fit within the line size.
+    Hang(Box<Layout>, Vec<Layout>, usize),
+    /// A layout shared by the alternatives of a Fit, the subtree is not
+    /// duplicated.
+    Shared(Rc<Layout>),
+    /// The tabular (multi-line) arrangement if all its lines fit within the
+    /// line size, otherwise the fallback arrangement. Like Fit, the choice is
+    /// made when rendering.
+    Table(Box<Layout>, Box<Layout>),
 }
 
 impl Layout {
@@ -63,6 +94,620 @@ impl Layout {
     pub fn space() -> Self {
         Self::Item(" ".into())
     }
+
+    pub fn fit(flat: Layout, broken: Layout) -> Self {
+        Self::Fit(Box::new(flat), Box::new(broken))
+    }
+
+    pub fn hang(head: Layout, list: Vec<Layout>, offset: usize) -> Self {
+        Self::Hang(Box::new(head), list, offset)
+    }
+
+    pub fn shared(l: Layout) -> Self {
+        Self::Shared(Rc::new(l))
+    }
+
+    pub fn table(table: Layout, fallback: Layout) -> Self {
+        Self::Table(Box::new(table), Box::new(fallback))
+    }
+
+    /// Returns a stack-safe traversal of the layout.
+    pub(crate) fn events(&self) -> Events<'_> {
+        Events {
+            stack: vec![Work::Layout(self)],
+        }
+    }
+
+    /// Returns the width of the layout rendered on a single line, or None if
+    /// the layout spans multiple lines.
+    pub(crate) fn flat_width(&self) -> Option<usize> {
+        let mut width = 0;
+        let mut events = self.events();
+
+        while let Some(event) = events.next() {
+            match event {
+                Event::Text(s) | Event::Separator(s) => {
+                    // #insight A comment extends to the end of the line.
+                    if s.contains('\n') || is_comment(s) {
+                        return None;
+                    }
+                    width += display_width(s);
+                }
+                Event::Ann(ann) => width += display_width(&format_annotations(ann)),
+                Event::Fit(flat, _) | Event::Table(flat, _) => events.push(flat),
+                Event::Mark => (),
+                Event::IndentStart(..)
+                | Event::IndentEnd(..)
+                | Event::HangStart(..)
+                | Event::HangEnd
+                | Event::LineStart => return None,
+            }
+        }
+
+        Some(width)
+    }
+}
+
+/// A rendering event, emitted by the traversal of a Layout.
+#[derive(Debug)]
+pub(crate) enum Event<'l> {
+    /// A text fragment.
+    Text(&'l str),
+    /// The separator between the children of a Row, Stack or Indent.
+    Separator(&'l str),
+    /// The start of an indentation block, with optional alignment.
+    IndentStart(Option<usize>),
+    /// The end of an indentation block, with optional alignment.
+    IndentEnd(Option<usize>),
+    /// The indentation before the children of an Indent and before an Apply.
+    LineStart,
+    Ann(&'l HashMap<String, Expr>),
+    /// A choice between the flat and the broken arrangement, the consumer
+    /// pushes the chosen layout back to the traversal.
+    Fit(&'l Layout, &'l Layout),
+    /// A choice between the tabular and the fallback arrangement, the
+    /// consumer pushes the chosen layout back to the traversal.
+    Table(&'l Layout, &'l Layout),
+    /// Marks the column of the start of a hanging layout.
+    Mark,
+    /// The start of a hanging alignment block, with the offset from the
+    /// marked column and the aligned children.
+    HangStart(usize, &'l [Layout]),
+    /// The end of a hanging alignment block.
+    HangEnd,
+}
+
+enum Work<'l> {
+    Layout(&'l Layout),
+    Event(Event<'l>),
+}
+
+/// A stack-safe traversal of a Layout, yields rendering events. An explicit
+/// work stack is used instead of recursion to support arbitrary nesting.
+pub(crate) struct Events<'l> {
+    stack: Vec<Work<'l>>,
+}
+
+impl<'l> Events<'l> {
+    /// Pushes a layout to be traversed next, e.g. the choice of a Fit event.
+    pub(crate) fn push(&mut self, layout: &'l Layout) {
+        self.stack.push(Work::Layout(layout));
+    }
+
+    fn push_children(&mut self, children: &'l [Layout], separator: &'l str, line_start: bool) {
+        for (i, child) in children.iter().enumerate().rev() {
+            self.stack.push(Work::Layout(child));
+            if line_start {
+                self.stack.push(Work::Event(Event::LineStart));
+            }
+            if i > 0 {
+                self.stack.push(Work::Event(Event::Separator(separator)));
+            }
+        }
+    }
+}
+
+impl<'l> Iterator for Events<'l> {
+    type Item = Event<'l>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let layout = match self.stack.pop()? {
+                Work::Event(event) => return Some(event),
+                Work::Layout(layout) => layout,
+            };
+
+            match layout {
+                Layout::Item(s) => return Some(Event::Text(s)),
+                Layout::Row(v, separator) => self.push_children(v, separator, false),
+                Layout::Stack(v) => self.push_children(v, "\n", false),
+                Layout::Indent(v, indent_size) => {
+                    self.stack.push(Work::Event(Event::IndentEnd(*indent_size)));
+                    self.push_children(v, "\n", true);
+                    return Some(Event::IndentStart(*indent_size));
+                }
+                Layout::Apply(l) => {
+                    self.stack.push(Work::Layout(l));
+                    return Some(Event::LineStart);
+                }
+                Layout::Ann(ann, l) => {
+                    self.stack.push(Work::Layout(l));
+                    return Some(Event::Ann(ann));
+                }
+                Layout::Separator => (),
+                Layout::Fit(flat, broken) => return Some(Event::Fit(flat, broken)),
+                Layout::Shared(layout) => self.stack.push(Work::Layout(layout)),
+                Layout::Table(table, fallback) => return Some(Event::Table(table, fallback)),
+                Layout::Hang(head, v, offset) => {
+                    self.stack.push(Work::Event(Event::HangEnd));
+                    self.push_children(v, "\n", true);
+                    self.stack.push(Work::Event(Event::HangStart(*offset, v)));
+                    self.stack.push(Work::Event(Event::Separator("\n")));
+                    self.stack.push(Work::Layout(head));
+                    return Some(Event::Mark);
+                }
+            }
+        }
+    }
+}
+
+/// Formats an atom (non-list) expression.
+fn format_atom(expr: &Expr) -> String {
+    match expr {
+        Expr::Comment(s, _) => s.clone(),
+        Expr::String(s) => format!("\"{}\"", escape_string(s)),
+        Expr::Symbol(s) => s.clone(),
+        Expr::Int(n) => n.to_string(),
+        // #insight `()` is the single instance of the Unit type `Nil`.
+        Expr::None => "()".to_string(),
+        Expr::Bool(b) => b.to_string(),
+        Expr::Float(n) => format_float(*n),
+        // #todo Handle keypaths, don't desugar.
+        Expr::KeySymbol(s) => format!(":{s}"),
+        Expr::Char(c) => format!(r#"(Char "{c}")"#),
+        _ => expr.to_string(),
+    }
+}
+
+/// Formats the sugar of a `Range` expression, e.g. `0..10|2`.
+fn format_range(args: &[&Expr]) -> String {
+    let mut range = format!("{}..{}", args[0], args[1]);
+    if let Some(step) = args.get(2) {
+        range = format!("{range}|{step}");
+    }
+    range
+}
+
+/// Returns true if the last text of the layout is a comment, it extends to
+/// the end of the line.
+fn ends_with_comment(layout: &Layout) -> bool {
+    let mut last = None;
+    let mut events = layout.events();
+
+    while let Some(event) = events.next() {
+        match event {
+            Event::Text(s) if !s.trim().is_empty() => last = Some(s),
+            Event::Fit(flat, _) | Event::Table(flat, _) => events.push(flat),
+            _ => (),
+        }
+    }
+
+    last.is_some_and(is_comment)
+}
+
+/// Returns true if the expression is a comment on the line where the previous
+/// expression ends.
+pub(crate) fn is_inline_comment(expr: &Expr, prev: &Expr) -> bool {
+    // #insight A text separator ends on the line of the next expression.
+    matches!(expr.unpack(), Expr::Comment(..))
+        && !matches!(prev.unpack(), Expr::TextSeparator)
+        && match (expr.range(), prev.range()) {
+            (Some(range), Some(prev_range)) => range.start.line == prev_range.end.line,
+            _ => false,
+        }
+}
+
+/// Returns true if the list is an `if` with a condition and one or two
+/// branches, without comments.
+fn is_chainable_if(exprs: &[Expr]) -> bool {
+    matches!(exprs.first().map(Expr::unpack), Some(Expr::Symbol(name)) if name == "if")
+        && (exprs.len() == 3 || exprs.len() == 4)
+        && exprs[1..].iter().all(|expr| {
+            !matches!(
+                expr.unpack(),
+                Expr::Comment(..) | Expr::TextSeparator | Expr::Annotation(..)
+            )
+        })
+}
+
+/// Returns the expressions of a chainable `if` list.
+fn chainable_if(expr: &Expr) -> Option<&[Expr]> {
+    match expr.unpack() {
+        Expr::List(exprs) if is_chainable_if(exprs) => Some(exprs),
+        _ => None,
+    }
+}
+
+/// Returns true if the list is a `match` with a scrutinee and complete
+/// pattern/body arms, otherwise it's arranged as a call.
+fn is_match(exprs: &[Expr]) -> bool {
+    let is_code = |expr: &&Expr| !matches!(expr.unpack(), Expr::Comment(..) | Expr::TextSeparator);
+
+    let Some(scrutinee) = exprs.get(1) else {
+        return false;
+    };
+
+    is_code(&scrutinee) && exprs[2..].iter().filter(is_code).count() % 2 == 0
+}
+
+/// Returns the layout of a `match` arm, the body is inline if it fits,
+/// otherwise indented under the pattern.
+fn match_arm(layout: Layout) -> Layout {
+    match layout {
+        Layout::Row(items, separator) if items.len() == 2 => {
+            // #insight The pattern and the body are shared, not duplicated.
+            let items: Vec<Layout> = items.into_iter().map(Layout::shared).collect();
+            let broken = vec![items[0].clone(), Layout::indent(vec![items[1].clone()])];
+            Layout::fit(Layout::Row(items, separator), Layout::Stack(broken))
+        }
+        // #insight Comments and arms with trailing comments are kept as-is.
+        layout => layout,
+    }
+}
+
+/// Returns the layout of a vertical call, the arguments are aligned with the
+/// first argument.
+fn hanging_call(head: Layout, mut args: Vec<Layout>) -> Layout {
+    let offset = head.flat_width().unwrap_or_default() + 1;
+    let head = Layout::row(vec![head, args.remove(0)]);
+    Layout::Stack(vec![
+        Layout::hang(head, args, offset),
+        Layout::apply(Layout::item(")")),
+    ])
+}
+
+/// Returns the items of an array expression.
+fn array_items(expr: &Expr) -> Option<&[Expr]> {
+    match expr.unpack() {
+        Expr::List(exprs) => match exprs.first().map(Expr::unpack) {
+            Some(Expr::Symbol(name)) if name == "Array" => Some(&exprs[1..]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns true if the expression is a scalar that can be a matrix cell.
+fn is_scalar(expr: &Expr) -> bool {
+    matches!(
+        expr.unpack(),
+        Expr::Int(..)
+            | Expr::Float(..)
+            | Expr::String(..)
+            | Expr::Symbol(..)
+            | Expr::KeySymbol(..)
+            | Expr::Bool(..)
+            | Expr::Char(..)
+    )
+}
+
+/// Returns true if the array (list) contains two or more rows, the rows are
+/// arrays of scalars with equal length.
+fn is_matrix(exprs: &[Expr]) -> bool {
+    let mut rows = exprs[1..].iter().map(array_items);
+    let Some(Some(first)) = rows.next() else {
+        return false;
+    };
+
+    exprs.len() > 2
+        && !first.is_empty()
+        && first.iter().all(is_scalar)
+        && rows
+            .all(|row| row.is_some_and(|row| row.len() == first.len() && row.iter().all(is_scalar)))
+}
+
+/// A cell of a grid, e.g. a matrix or a table of records.
+struct Cell {
+    /// A prefix that is not aligned, e.g. the key of a record value.
+    prefix: String,
+    value: String,
+    is_number: bool,
+}
+
+impl Cell {
+    fn new(prefix: String, expr: &Expr) -> Self {
+        Self {
+            prefix,
+            value: format_atom(expr.unpack()),
+            is_number: matches!(expr.unpack(), Expr::Int(..) | Expr::Float(..)),
+        }
+    }
+}
+
+/// Formats the rows of a grid, the columns are padded to equal width,
+/// numbers are right-aligned, the rest left-aligned.
+fn format_grid(rows: &[Vec<Cell>], open: &str, close: &str) -> Vec<Layout> {
+    let column_count = rows[0].len();
+    let widths: Vec<usize> = (0..column_count)
+        .map(|j| {
+            rows.iter()
+                .map(|row| display_width(&row[j].value))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    // #insight
+    // The padding is a separate (whitespace) item, the compact formatter
+    // collapses it.
+    rows.iter()
+        .map(|row| {
+            let mut items = vec![Layout::item(open)];
+            for (j, cell) in row.iter().enumerate() {
+                if j > 0 {
+                    items.push(Layout::space());
+                }
+                if !cell.prefix.is_empty() {
+                    items.push(Layout::item(&cell.prefix));
+                    items.push(Layout::space());
+                }
+                let padding = Layout::item(" ".repeat(widths[j] - display_width(&cell.value)));
+                if cell.is_number {
+                    items.push(padding);
+                    items.push(Layout::item(&cell.value));
+                } else {
+                    items.push(Layout::item(&cell.value));
+                    // #insight The last column is not padded, before the close.
+                    if j < column_count - 1 {
+                        items.push(padding);
+                    }
+                }
+            }
+            items.push(Layout::item(close));
+            Layout::join(items)
+        })
+        .collect()
+}
+
+/// Formats the rows of a matrix as a grid.
+fn format_matrix(rows: &[Expr]) -> Vec<Layout> {
+    // safe to unwrap, checked by is_matrix.
+    let rows: Vec<Vec<Cell>> = rows
+        .iter()
+        .map(|row| {
+            array_items(row)
+                .unwrap()
+                .iter()
+                .map(|expr| Cell::new(String::new(), expr))
+                .collect()
+        })
+        .collect();
+
+    format_grid(&rows, "[", "]")
+}
+
+/// Returns the key-value pairs of a Map expression (record) with scalar
+/// values, without comments.
+fn record_pairs(expr: &Expr) -> Option<Vec<(String, &Expr)>> {
+    let Expr::List(exprs) = expr.unpack() else {
+        return None;
+    };
+
+    match exprs.first().map(Expr::unpack) {
+        Some(Expr::Symbol(name)) if name == "Map" => (),
+        _ => return None,
+    }
+
+    let items = &exprs[1..];
+    if items.len() % 2 != 0 || !items.iter().all(is_scalar) {
+        return None;
+    }
+
+    Some(
+        items
+            .chunks(2)
+            .map(|pair| (format_atom(pair[0].unpack()), &pair[1]))
+            .collect(),
+    )
+}
+
+/// Formats an array of records (Maps with the same keys) as a table, one
+/// record per line, the keys in consistent order.
+fn format_records(items: &[Expr], order: &MapKeyOrder) -> Option<Vec<Layout>> {
+    let records: Vec<Vec<(String, &Expr)>> =
+        items.iter().map(record_pairs).collect::<Option<_>>()?;
+
+    if records.len() < 2 || records[0].is_empty() {
+        return None;
+    }
+
+    // The keys of the first record define the order of the columns.
+    let mut keys: Vec<&str> = records[0].iter().map(|(key, _)| key.as_str()).collect();
+    if *order != MapKeyOrder::Source {
+        keys.sort_by(|a, b| key_rank(a, order).cmp(&key_rank(b, order)));
+    }
+
+    let rows = records
+        .iter()
+        .map(|record| {
+            if record.len() != keys.len() {
+                return None;
+            }
+            keys.iter()
+                .map(|key| {
+                    let (_, value) = record.iter().find(|(k, _)| k == key)?;
+                    Some(Cell::new(key.to_string(), value))
+                })
+                .collect::<Option<Vec<_>>>()
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(format_grid(&rows, "{", "}"))
+}
+
+/// Returns true if the items are atoms that can be packed into lines, e.g.
+/// numbers or short symbols.
+fn is_fillable(items: &[Layout]) -> bool {
+    items.len() > 1
+        && items
+            .iter()
+            .all(|item| matches!(item, Layout::Item(s) if !is_comment(s)))
+}
+
+/// Packs the items into lines that fit within the line size, the lines are
+/// continued at the current indentation.
+fn fill(items: Vec<Layout>) -> Layout {
+    let mut items = items.into_iter();
+    let mut layouts: Vec<Layout> = items.next().into_iter().collect();
+
+    for item in items {
+        let flat = Layout::join(vec![Layout::space(), item.clone()]);
+        let broken = Layout::Stack(vec![Layout::item(""), Layout::apply(item)]);
+        layouts.push(Layout::fit(flat, broken));
+    }
+
+    Layout::join(layouts)
+}
+
+/// Returns true if the column is the start of a token of the line, e.g. the
+/// alignment column of `let` bindings.
+fn is_token_start(line: &str, col: usize) -> bool {
+    let mut prev = " ";
+    let mut i = 0;
+    for g in line.graphemes(true) {
+        if i == col {
+            let is_space = |g: &str| g.chars().all(char::is_whitespace);
+            return !is_space(g) && (is_space(prev) || "([{".contains(prev));
+        }
+        i += display_width(g);
+        prev = g;
+    }
+    false
+}
+
+/// Returns the line with the leading tabs expanded to spaces.
+fn expand_tabs(line: &str, tab_width: usize) -> String {
+    let content = line.trim_start_matches('\t');
+    let tabs = line.len() - content.len();
+    format!("{}{content}", " ".repeat(tabs * tab_width))
+}
+
+/// Returns true if the source of a form satisfies the layout constraints: the
+/// lines fit within the line size, there is no trailing whitespace, and
+/// continuation lines are indented with spaces, by a multiple of the indent
+/// size or aligned with a token of the previous line. If tabs are used, the
+/// indentation levels are tabs, and only the alignment uses spaces.
+fn is_conforming(text: &str, options: &Options) -> bool {
+    let mut prev_line = String::new();
+
+    text.lines().enumerate().all(|(i, line)| {
+        let (line, tab_indent) = if options.use_tabs {
+            let tab_width = options.indent_width();
+            let tabs = line.len() - line.trim_start_matches('\t').len();
+            (expand_tabs(line, tab_width), tabs * tab_width)
+        } else {
+            (line.to_owned(), 0)
+        };
+        let content = line.trim_start_matches(' ');
+        let indent = line.len() - content.len();
+
+        let is_level = if options.use_tabs {
+            indent == tab_indent
+        } else {
+            indent.checked_rem(options.indent_size) == Some(0)
+        };
+
+        let is_indented = content.is_empty()
+            || (indent > 0 || content.starts_with([')', ']', '}']))
+                && (is_level || is_token_start(&prev_line, indent));
+
+        let is_conforming = display_width(&line) <= options.line_size
+            && !line.ends_with(char::is_whitespace)
+            && !content.starts_with('\t')
+            && (i == 0 || is_indented);
+
+        if !content.is_empty() {
+            prev_line = line;
+        }
+
+        is_conforming
+    })
+}
+
+/// Merges consecutive full-line comments into a single item, the lines are
+/// reflowed as paragraphs when rendered.
+fn merge_comment_lines(layouts: Vec<Layout>) -> Vec<Layout> {
+    let mut merged: Vec<Layout> = Vec::with_capacity(layouts.len());
+
+    for layout in layouts {
+        if let (Layout::Item(item), Some(Layout::Item(prev))) = (&layout, merged.last_mut()) {
+            if is_comment(item) && is_comment(prev) {
+                prev.push('\n');
+                prev.push_str(item);
+                continue;
+            }
+        }
+        merged.push(layout);
+    }
+
+    merged
+}
+
+/// Returns the (rendered) key of a pair layout.
+fn pair_key(layout: &Layout) -> &str {
+    match layout {
+        Layout::Row(v, ..) | Layout::Stack(v) => v.first().map(pair_key).unwrap_or_default(),
+        Layout::Item(s) => s,
+        _ => "",
+    }
+}
+
+/// Returns the sort rank of a key, prioritized keys come first.
+fn key_rank<'k>(key: &'k str, order: &MapKeyOrder) -> (usize, &'k str) {
+    if let MapKeyOrder::Priority(priority_keys) = order {
+        let name = key.trim_start_matches(':');
+        if let Some(i) = priority_keys
+            .iter()
+            .position(|k| k.trim_start_matches(':') == name)
+        {
+            return (i, key);
+        }
+        return (priority_keys.len(), key);
+    }
+    (0, key)
+}
+
+/// Sorts pair layouts by key, full-line comments stay attached to the
+/// following pair.
+fn sort_pairs(pairs: Vec<Layout>, order: &MapKeyOrder) -> Vec<Layout> {
+    if *order == MapKeyOrder::Source {
+        return pairs;
+    }
+
+    let mut groups: Vec<Vec<Layout>> = Vec::new();
+    let mut group = Vec::new();
+
+    for layout in pairs {
+        let is_comment = matches!(&layout, Layout::Item(s) if s.starts_with(';'));
+        group.push(layout);
+        if !is_comment {
+            groups.push(std::mem::take(&mut group));
+        }
+    }
+
+    // #insight The sort is stable, pairs with equal keys retain their order.
+    groups.sort_by(|a, b| {
+        let a = key_rank(pair_key(a.last().unwrap()), order);
+        let b = key_rank(pair_key(b.last().unwrap()), order);
+        a.cmp(&b)
+    });
+
+    let mut sorted: Vec<Layout> = groups.into_iter().flatten().collect();
+
+    // Comments after the last pair stay at the end.
+    sorted.append(&mut group);
+
+    sorted
 }
 
 // #todo should allow for multiple concurrent modes?
@@ -87,43 +732,309 @@ pub struct Arranger<'a> {
     // #todo consider different names, e.g. `flavor`?
     // #todo use a builder pattern.
     pub dialect: Dialect,
+    // The arranged expressions.
+    list: &'a [Expr],
     exprs: PutBackIterator<'a, Expr>,
     mode: ArrangerMode,
+    // #insight The options are shared with the nested (list) arrangers.
+    options: Rc<Options>,
+    // Sort the keys of this Map, requested by a `#sort-keys` annotation.
+    sort_keys: bool,
+    // A `#sort-keys` annotation applies to the next list.
+    sort_next_keys: bool,
+    // The nesting depth of the arranged list.
+    depth: usize,
+    // The source index of the end of the arranged list.
+    end: Option<usize>,
+    // Comments from the token stream, missing from the expressions.
+    // #insight The orphan comments are shared with the nested arrangers.
+    orphans: Rc<RefCell<VecDeque<Comment>>>,
+    // Layouts to be returned before the next expression.
+    pending: VecDeque<Layout>,
+    // The source of the expressions, used to preserve the source layout.
+    source: Option<Rc<str>>,
 }
 
 impl<'a> Arranger<'a> {
     pub fn new(exprs: &'a [Expr], dialect: Dialect) -> Self {
         Self {
             dialect,
+            list: exprs,
             exprs: PutBackIterator::new(exprs),
             mode: ArrangerMode::Default,
+            options: Rc::new(Options::default()),
+            sort_keys: false,
+            sort_next_keys: false,
+            depth: 0,
+            end: None,
+            orphans: Rc::new(RefCell::new(VecDeque::new())),
+            pending: VecDeque::new(),
+            source: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = Rc::new(options);
+        self
+    }
+
+    /// Reconciles the comments missing from the expressions, see
+    /// `comments::orphan_comments`.
+    pub(crate) fn with_orphan_comments(mut self, orphans: VecDeque<Comment>) -> Self {
+        self.orphans = Rc::new(RefCell::new(orphans));
+        self
+    }
+
+    /// The source of the expressions, to preserve the layout of conforming
+    /// top-level forms.
+    pub(crate) fn with_source(mut self, source: Rc<str>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Returns the source of a top-level form as-is, if the source layout
+    /// should be preserved and it satisfies the layout constraints.
+    fn preserved_layout(&mut self, expr: &Expr) -> Option<Layout> {
+        if !self.options.preserve_layout || self.depth > 0 {
+            return None;
+        }
+
+        let Expr::List(..) = expr.unpack() else {
+            return None;
+        };
+
+        let range = expr.range()?;
+        if range.start.col != 0 {
+            return None;
+        }
+
+        let text = self
+            .source
+            .as_ref()?
+            .get(range.start.index..range.end.index)?;
+        if !is_conforming(text, &self.options) {
+            return None;
+        }
+
+        // #insight The orphan comments within the form are retained in the source.
+        let mut orphans = self.orphans.borrow_mut();
+        while orphans.front().is_some_and(|c| c.index < range.end.index) {
+            orphans.pop_front();
+        }
+
+        Some(Layout::item(text))
+    }
+
+    /// Returns the layout of a comment, normalizes the comment marker by
+    /// position if requested.
+    fn comment_layout(&self, text: &str, inline: bool) -> Layout {
+        Layout::Item(self.format_comment(text, inline))
+    }
+
+    /// Formats a comment, normalizes the marker and spacing if requested.
+    fn format_comment(&self, text: &str, inline: bool) -> String {
+        let Some(markers) = &self.options.comment_markers else {
+            return text.to_owned();
+        };
+
+        let marker = if inline {
+            &markers.inline
+        } else if self.depth == 0 {
+            &markers.top_level
+        } else {
+            &markers.nested
+        };
+
+        normalize_comment(text, marker)
+    }
+
+    /// Formats a (deeply nested) expression on a single line, uses an explicit
+    /// work stack instead of recursion. The special forms keep their sugar.
+    fn format_flat(&self, expr: &Expr) -> String {
+        enum Work<'e> {
+            Expr(&'e Expr, Option<&'e Expr>),
+            Text(&'static str),
+        }
+
+        let mut output = String::new();
+        let mut stack = vec![Work::Expr(expr, None)];
+
+        while let Some(work) = stack.pop() {
+            let (expr, prev) = match work {
+                Work::Text(s) => {
+                    output.push_str(s);
+                    continue;
+                }
+                Work::Expr(expr, prev) => (expr.unpack(), prev),
+            };
+
+            let exprs = match expr {
+                Expr::List(exprs) if !exprs.is_empty() => exprs,
+                Expr::TextSeparator => continue,
+                Expr::Comment(text, _) => {
+                    let inline = prev.is_some_and(|prev| is_inline_comment(expr, prev));
+                    output.push_str(&self.format_comment(text, inline));
+                    // #insight A comment extends to the end of the line.
+                    output.push('\n');
+                    continue;
+                }
+                _ => {
+                    output.push_str(&format_atom(expr));
+                    continue;
+                }
+            };
+
+            let (open, close, items) = match exprs[0].unpack() {
+                Expr::Symbol(name) if name == "Range" => {
+                    // #insight Comments within the range are trailing.
+                    let (comments, args): (Vec<&Expr>, Vec<&Expr>) = exprs[1..]
+                        .iter()
+                        .partition(|expr| matches!(expr.unpack(), Expr::Comment(..)));
+                    output.push_str(&format_range(&args));
+                    for comment in comments {
+                        if let Expr::Comment(text, _) = comment.unpack() {
+                            output.push(' ');
+                            output.push_str(&self.format_comment(text, true));
+                            output.push('\n');
+                        }
+                    }
+                    continue;
+                }
+                Expr::Symbol(name) if name == "Array" => ("[", "]", &exprs[1..]),
+                Expr::Symbol(name) if name == "Map" => ("{", "}", &exprs[1..]),
+                Expr::Symbol(name) if name == "quot" => ("'", "", &exprs[1..]),
+                Expr::Symbol(name) if name == "unquot" => ("$", "", &exprs[1..]),
+                _ => ("(", ")", &exprs[..]),
+            };
+
+            stack.push(Work::Text(close));
+            let items: Vec<&Expr> = items
+                .iter()
+                .filter(|expr| !matches!(expr.unpack(), Expr::TextSeparator))
+                .collect();
+            for (i, item) in items.iter().enumerate().rev() {
+                stack.push(Work::Expr(item, i.checked_sub(1).map(|i| items[i])));
+                if i > 0 {
+                    stack.push(Work::Text(" "));
+                }
+            }
+            stack.push(Work::Text(open));
+        }
+
+        output
+    }
+
+    /// Returns the layout of an inline (trailing) comment expression.
+    fn inline_comment_from_expr(&self, expr: &Expr) -> Layout {
+        match expr.unpack() {
+            Expr::Comment(text, _) => self.comment_layout(text, true),
+            expr => Layout::Item(format_atom(expr)),
+        }
+    }
+
+    /// Queues the orphan comments before the source index, returns true if
+    /// a comment is queued.
+    fn queue_orphans_before(&mut self, index: usize) -> bool {
+        let mut orphans = self.orphans.borrow_mut();
+        let mut queued = false;
+
+        while let Some(comment) = orphans.pop_front() {
+            if comment.index >= index {
+                orphans.push_front(comment);
+                break;
+            }
+            let layout = self.comment_layout(&comment.text, false);
+            self.pending.push_back(layout);
+            queued = true;
+        }
+
+        queued
+    }
+
+    /// Queues the orphan comments before the expression (leading comments),
+    /// returns true if a comment is queued.
+    fn queue_leading_orphans(&mut self, expr: &Expr) -> bool {
+        match expr.range() {
+            Some(range) => self.queue_orphans_before(range.start.index),
+            None => false,
+        }
+    }
+
+    /// Queues the orphan comments before the end of the list (dangling
+    /// comments).
+    fn queue_dangling_orphans(&mut self) {
+        self.queue_orphans_before(self.end.unwrap_or(usize::MAX));
+    }
+
+    /// Takes the orphan comment after the expression, on the same line
+    /// (trailing comment).
+    fn take_trailing_orphan(&mut self, expr: &Expr) -> Option<Layout> {
+        if let Expr::TextSeparator = expr.unpack() {
+            return None;
+        }
+
+        let range = expr.range()?;
+        let mut orphans = self.orphans.borrow_mut();
+        let comment = orphans.front()?;
+
+        if comment.line == range.end.line && comment.index >= range.end.index {
+            let comment = orphans.pop_front()?;
+            drop(orphans);
+            Some(self.comment_layout(&comment.text, true))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the next expression, comments are skipped if the options
+    /// request to strip them.
+    fn next_expr(&mut self) -> Option<&'a Expr> {
+        loop {
+            let expr = self.exprs.next()?;
+
+            let is_comment = matches!(expr.unpack(), Expr::Comment(..)) || is_doc_annotation(expr);
+
+            if !(self.options.strip_comments && is_comment) {
+                return Some(expr);
+            }
         }
     }
 
     fn arrange_next(&mut self) -> Option<Layout> {
-        let expr0 = self.exprs.next()?;
+        if let Some(layout) = self.pending.pop_front() {
+            return Some(layout);
+        }
+
+        let Some(expr0) = self.next_expr() else {
+            self.queue_dangling_orphans();
+            return self.pending.pop_front();
+        };
+
+        if self.queue_leading_orphans(expr0) {
+            self.exprs.put_back(expr0);
+            return self.pending.pop_front();
+        }
 
-        let layout = self.layout_from_expr(expr0);
+        let layout = match self.preserved_layout(expr0) {
+            Some(layout) => layout,
+            None => self.layout_from_expr(expr0),
+        };
 
         // #insight
         // Fetch the next expression and try to detect an inline comment.
         // If an inline comment is found, force vertical layout.
-        if let Some(expr1) = self.exprs.next() {
-            match expr1.unpack() {
-                Expr::Comment(..) => {
-                    if expr1.range().unwrap().start.line == expr0.range().unwrap().start.line {
-                        let comment = self.layout_from_expr(expr1);
-                        return Some(Layout::row(vec![layout, comment]));
-                    } else {
-                        self.exprs.put_back(expr1);
-                    }
-                }
-                _ => {
-                    self.exprs.put_back(expr1);
-                }
+        if let Some(expr1) = self.next_expr() {
+            if is_inline_comment(expr1, expr0) {
+                let comment = self.inline_comment_from_expr(expr1);
+                return Some(Layout::row(vec![layout, comment]));
             }
+            self.exprs.put_back(expr1);
         };
 
+        if let Some(comment) = self.take_trailing_orphan(expr0) {
+            return Some(Layout::row(vec![layout, comment]));
+        }
+
         Some(layout)
     }
 
@@ -156,6 +1067,10 @@ impl<'a> Arranger<'a> {
             layouts.push(layout);
         }
 
+        if self.options.reflow_comments {
+            layouts = merge_comment_lines(layouts);
+        }
+
         (layouts, force_vertical)
     }
 
@@ -183,11 +1098,11 @@ impl<'a> Arranger<'a> {
             // force vertical if there is a full-line comment.
             // force vertical if an item length exceeds a threshold.
             if let Layout::Item(item) = &layout {
-                items_cumulative_length += item.len();
+                items_cumulative_length += display_width(item);
 
                 force_vertical = force_vertical
                     || item.starts_with(';') // is comment?
-                    || (self.mode != ArrangerMode::Inline && item.len() > item_length_vertical_arrange_threshold);
+                    || (self.mode != ArrangerMode::Inline && display_width(item) > item_length_vertical_arrange_threshold);
                 // is long item?
             }
 
@@ -196,10 +1111,14 @@ impl<'a> Arranger<'a> {
 
         if self.mode != ArrangerMode::Inline {
             // #todo find a good threshold!
-            // #todo also support wrapping to next line.
+            // #insight See `Options::fill_arrays` for wrapping to the next line.
             force_vertical = force_vertical || items_cumulative_length > 32;
         }
 
+        if self.options.reflow_comments {
+            layouts = merge_comment_lines(layouts);
+        }
+
         (layouts, force_vertical)
     }
 
@@ -212,7 +1131,7 @@ impl<'a> Arranger<'a> {
 
         while let Expr::Annotation(..) = expr.unpack() {
             annotated.push(self.layout_from_expr(expr));
-            expr = self.exprs.next()?;
+            expr = self.next_expr()?;
         }
 
         // #todo Pretty-print the value/payload of the annotation.
@@ -225,11 +1144,24 @@ impl<'a> Arranger<'a> {
         }
     }
 
-    // #todo add doc-comment.
+    /// Arranges the next key-value pair, e.g. of a `let`, `cond` or Map.
+    /// Full-line comments are arranged as separate items.
     fn arrange_next_pair(&mut self) -> Option<Layout> {
         // #todo Add unit-test just for this method.
 
-        let expr = self.exprs.next()?;
+        if let Some(layout) = self.pending.pop_front() {
+            return Some(layout);
+        }
+
+        let Some(expr) = self.next_expr() else {
+            self.queue_dangling_orphans();
+            return self.pending.pop_front();
+        };
+
+        if self.queue_leading_orphans(expr) {
+            self.exprs.put_back(expr);
+            return self.pending.pop_front();
+        }
 
         // #insight Handles (skips) full line comments.
         // #todo Needs more elegant solution.
@@ -239,28 +1171,65 @@ impl<'a> Arranger<'a> {
 
         let mut tuple = Vec::new();
 
+        let key = expr;
+
         tuple.push(self.maybe_annotated_layout_from_expr(expr)?);
 
-        let expr = self.exprs.next()?;
+        // #insight
+        // A comment between the key and the value is trailing if it's on the
+        // line of the key, otherwise it's a leading comment of the value.
+        let mut trailing_comments = Vec::new();
+        let mut value_comments = Vec::new();
+        let mut expr = self.next_expr()?;
+        while let Expr::Comment(..) = expr.unpack() {
+            if is_inline_comment(expr, key) {
+                trailing_comments.push(self.inline_comment_from_expr(expr));
+            } else {
+                value_comments.push(self.layout_from_expr(expr));
+            }
+            expr = self.next_expr()?;
+        }
+
+        if let Some(comment) = self.take_trailing_orphan(key) {
+            trailing_comments.push(comment);
+        }
+
+        // #insight The pending queue is empty, see above.
+        if self.queue_leading_orphans(expr) {
+            value_comments.extend(self.pending.drain(..));
+        }
+
         tuple.push(self.maybe_annotated_layout_from_expr(expr)?);
 
         // Try to skip trailing comments.
-        if let Some(expr) = self.exprs.next() {
-            match expr.unpack() {
-                Expr::Comment(..) => {
-                    if expr.range().unwrap().start.line == expr.range().unwrap().start.line {
-                        tuple.push(self.layout_from_expr(expr));
-                    } else {
-                        self.exprs.put_back(expr);
-                    }
-                }
-                _ => {
-                    self.exprs.put_back(expr);
-                }
+        if let Some(expr) = self.next_expr() {
+            if is_inline_comment(expr, key) {
+                // #insight A comment on the line of the key is trailing,
+                // otherwise it's a full-line comment of the next pair.
+                tuple.push(self.inline_comment_from_expr(expr));
+            } else {
+                self.exprs.put_back(expr);
             }
         };
 
-        Some(Layout::row(tuple))
+        tuple.append(&mut trailing_comments);
+
+        let pair = if value_comments.is_empty() {
+            Layout::row(tuple)
+        } else {
+            // The value, with its leading comments, is indented under the key.
+            let key = tuple.remove(0);
+            value_comments.push(Layout::row(tuple));
+            Layout::Stack(vec![key, Layout::indent(value_comments)])
+        };
+
+        if self.pending.is_empty() {
+            Some(pair)
+        } else {
+            // Return the leading comments first.
+            self.pending.push_back(pair);
+            self.pending.pop_front()
+        }
     }
 
     fn arrange_all_pairs(&mut self) -> (Vec<Layout>, bool) {
@@ -269,22 +1238,37 @@ impl<'a> Arranger<'a> {
         let mut should_force_vertical = false;
 
         while let Some(layout) = self.arrange_next_pair() {
-            if let Layout::Row(items, ..) = &layout {
-                if items.len() > 2 {
+            match &layout {
+                Layout::Row(items, ..) if items.len() > 2 => {
                     // If a pair has an inline comments, force vertical layout
                     should_force_vertical = true;
                 }
+                Layout::Item(item) if item.starts_with(';') => {
+                    // If there is a full-line comment, force vertical layout.
+                    should_force_vertical = true;
+                }
+                Layout::Stack(..) => {
+                    // If a value has leading comments, force vertical layout.
+                    should_force_vertical = true;
+                }
+                _ => (),
             };
 
             layouts.push(layout);
         }
 
+        if self.options.reflow_comments {
+            layouts = merge_comment_lines(layouts);
+        }
+
         (layouts, should_force_vertical)
     }
 
     fn arrange_list(&mut self) -> Layout {
-        // #insight not need to check here.
-        let expr = self.exprs.next().unwrap();
+        // #insight The list is empty if all comments are stripped.
+        let Some(expr) = self.next_expr() else {
+            return Layout::item("()");
+        };
 
         let mut layouts = Vec::new();
 
@@ -296,20 +1280,39 @@ impl<'a> Arranger<'a> {
         // #todo #warning (Func [...] ...) generate an Expr::Type("Func") !!
 
         match head {
-            Expr::Symbol(name) if name == "quot" => {
-                // #todo this is a temp solution, ideally it should recourse into arrange_list again.
-                // Always arrange a `quot` block horizontally.
-                let (exprs, _) = self.arrange_all();
-                layouts.push(Layout::item("'"));
-                layouts.push(Layout::row(exprs));
-                Layout::join(layouts)
+            Expr::Comment(..) => {
+                // #insight
+                // A list that starts with a comment (e.g. a comment-only list)
+                // is arranged vertically, the comments are kept inside the
+                // delimiters.
+                self.exprs.put_back(expr);
+                let (items, _) = self.arrange_all();
+                layouts.push(Layout::item("("));
+                layouts.push(Layout::indent(items));
+                layouts.push(Layout::apply(Layout::item(")")));
+                Layout::Stack(layouts)
             }
-            Expr::Symbol(name) if name == "unquot" => {
-                // Always arrange a `unquot` block horizontally.
-                let (exprs, _) = self.arrange_all();
-                layouts.push(Layout::item("$"));
-                layouts.push(Layout::row(exprs));
-                Layout::join(layouts)
+            Expr::Symbol(name) if name == "quot" || name == "unquot" => {
+                // #insight
+                // The quoted (or unquoted) expression is arranged recursively,
+                // with the same rules as ordinary code, nested quasi-quote and
+                // unquote levels included.
+                let (mut exprs, _) = self.arrange_all();
+                if exprs.len() == 1 && !ends_with_comment(&exprs[0]) {
+                    let prefix = if name == "quot" { "'" } else { "$" };
+                    layouts.push(Layout::item(prefix));
+                    layouts.push(exprs.remove(0));
+                    Layout::join(layouts)
+                } else {
+                    // #insight
+                    // A quoted expression with comments, or with a trailing
+                    // comment that would swallow the rest of the line, cannot
+                    // use the prefix syntax, arrange as a call.
+                    layouts.push(Layout::item(format!("({name}")));
+                    layouts.push(Layout::indent(exprs));
+                    layouts.push(Layout::apply(Layout::item(")")));
+                    Layout::Stack(layouts)
+                }
             }
             Expr::Symbol(name) if name == "do" => {
                 // Always arrange a `do` block vertically.
@@ -319,31 +1322,43 @@ impl<'a> Arranger<'a> {
                 layouts.push(Layout::apply(Layout::item(")")));
                 Layout::Stack(layouts)
             }
+            Expr::Symbol(name) if name == "if" && self.is_if_chain() => self.arrange_if_chain(),
             // #todo #hack super nasty way to handle both Symbol and Type.
             // #todo #warning (Func [...] ...) generate an Expr::Type("Func") !!
             Expr::Symbol(name) | Expr::Type(name)
                 if name == "if" || name == "for" || name == "Func" =>
             {
                 // The first expr is rendered inline, the rest are rendered vertically.
-                layouts.push(Layout::row(vec![
-                    Layout::item(format!("({name}")),
-                    // #todo special handling for `for` also needed, separate from Func.
-                    // #todo could set mode here!
-                    // #todo #hack nasty, refactor!
-                    if name == "Func" || name == "for" {
-                        let old_mode = self.mode;
-                        self.mode = ArrangerMode::Inline;
-                        let layout = self.arrange_next().unwrap();
-                        self.mode = old_mode;
-                        layout
-                    } else {
-                        self.arrange_next().unwrap()
-                    },
-                ]));
+                // #todo special handling for `for` also needed, separate from Func.
+                // #todo could set mode here!
+                // #todo #hack nasty, refactor!
+                let first = if name == "Func" || name == "for" {
+                    let old_mode = self.mode;
+                    self.mode = ArrangerMode::Inline;
+                    let layout = self.arrange_next();
+                    self.mode = old_mode;
+                    layout
+                } else {
+                    self.arrange_next()
+                };
+                // #insight The first expr and the block may be missing, e.g.
+                // if their comments are stripped.
+                let mut head = vec![Layout::item(format!("({name}"))];
+                head.extend(first);
+                layouts.push(Layout::row(head));
                 let (block, should_force_vertical) = self.arrange_all();
 
                 // #todo consider making `if` always multiline? no.
 
+                // #insight
+                // An `if` with a single branch pair is arranged on one line,
+                // if it fits.
+                let is_collapsible = name == "if"
+                    && self.options.collapse_short_ifs
+                    && !should_force_vertical
+                    && block.len() == 2
+                    && self.mode != ArrangerMode::Let;
+
                 let should_force_vertical = should_force_vertical || block.len() > 1;
 
                 // #todo reconsider forced-multiline for `for`.
@@ -354,76 +1369,112 @@ impl<'a> Arranger<'a> {
 
                 let should_force_vertical = should_force_vertical || self.mode == ArrangerMode::Let;
 
-                if should_force_vertical {
+                if block.is_empty() {
+                    // #insight A trailing comment of the head extends to the
+                    // end of the line.
+                    if layouts[0].flat_width().is_some() {
+                        layouts.push(Layout::item(")"));
+                        Layout::join(layouts)
+                    } else {
+                        layouts.push(Layout::apply(Layout::item(")")));
+                        Layout::Stack(layouts)
+                    }
+                } else if is_collapsible {
+                    // #insight The head and the block are shared, not duplicated.
+                    layouts = layouts.into_iter().map(Layout::shared).collect();
+                    let block: Vec<Layout> = block.into_iter().map(Layout::shared).collect();
+                    let flat = Layout::join(vec![
+                        layouts[0].clone(),
+                        Layout::space(),
+                        Layout::row(block.clone()),
+                        Layout::item(")"),
+                    ]);
+                    layouts.push(Layout::indent(block));
+                    layouts.push(Layout::apply(Layout::item(")")));
+                    Layout::fit(flat, Layout::Stack(layouts))
+                } else if should_force_vertical {
                     layouts.push(Layout::indent(block));
                     layouts.push(Layout::apply(Layout::item(")")));
                     Layout::Stack(layouts)
                 } else {
-                    layouts.push(Layout::item(" "));
-                    layouts.push(block[0].clone());
+                    if let Some(layout) = block.into_iter().next() {
+                        layouts.push(Layout::item(" "));
+                        layouts.push(layout);
+                    }
                     layouts.push(Layout::item(")"));
                     Layout::join(layouts)
                 }
             }
             Expr::Symbol(name) if name == "Range" => {
                 // #todo support open-ended ranges.
-                // safe to unwrap, it's already parsed.
-                let start = self.exprs.next().unwrap();
-                let end = self.exprs.next().unwrap();
-                let mut range = format!("{start}..{end}");
-                if let Some(step) = self.exprs.next() {
-                    range = format!("{range}|{step}");
+                // #insight Comments within the range are trailing.
+                let mut args = Vec::new();
+                let mut comments = Vec::new();
+                while let Some(expr) = self.next_expr() {
+                    if let Expr::Comment(..) = expr.unpack() {
+                        comments.push(self.inline_comment_from_expr(expr));
+                    } else {
+                        args.push(expr);
+                    }
+                }
+                // safe to index, it's already parsed.
+                let range = format_range(&args);
+                if comments.is_empty() {
+                    Layout::Item(range)
+                } else {
+                    comments.insert(0, Layout::Item(range));
+                    Layout::row(comments)
                 }
-                Layout::Item(range)
+            }
+            Expr::Symbol(name)
+                if name == "Array"
+                    && self.mode != ArrangerMode::Inline
+                    && !self.has_orphans()
+                    && is_matrix(self.list) =>
+            {
+                self.arrange_matrix()
             }
             Expr::Symbol(name) if name == "Array" => {
                 // #todo more sophisticated Array formatting needed.
-                // Try to format the array horizontally.
-                layouts.push(Layout::item("["));
-                // let (items, should_force_vertical) = self.arrange_all();
-                let (items, should_force_vertical) = self.arrange_all_array();
-
-                // #todo consider allowing horizontal for only one element.
-                // For `data` dialect always force vertical.
-                let should_force_vertical = should_force_vertical || self.dialect == Dialect::Data;
-
-                if !items.is_empty() {
-                    if should_force_vertical {
-                        layouts.push(Layout::indent(items));
-                        layouts.push(Layout::apply(Layout::item("]")));
-                        Layout::Stack(layouts)
-                    } else {
-                        match &items[0] {
-                            // Heuristic: if the array includes stacks, arrange
-                            // vertically.
-                            Layout::Stack(..) | Layout::Indent(..) => {
-                                layouts.push(Layout::indent(items));
-                                layouts.push(Layout::apply(Layout::item("]")));
-                                Layout::Stack(layouts)
-                            }
-                            _ => {
-                                layouts.push(Layout::row(items));
-                                layouts.push(Layout::item("]"));
-                                Layout::join(layouts)
-                            }
-                        }
-                    }
-                } else {
-                    layouts.push(Layout::item("]"));
-                    Layout::join(layouts)
+                let records = self.arrange_records();
+                let array = self.arrange_array();
+                match records {
+                    Some(table) => Layout::table(table, array),
+                    None => array,
                 }
             }
             Expr::Symbol(name) if name == "Map" => {
-                // #todo in data mode consider formatting empty Map like this: {}
                 let (bindings, should_force_vertical) = self.arrange_all_pairs();
 
+                // A `#sort-keys` annotation requests alphabetical order for
+                // this Map.
+                let bindings =
+                    if self.sort_keys && self.options.map_key_order == MapKeyOrder::Source {
+                        sort_pairs(bindings, &MapKeyOrder::Alphabetical)
+                    } else {
+                        sort_pairs(bindings, &self.options.map_key_order)
+                    };
+
                 // If more than 2 bindings force vertical.
                 let should_force_vertical = should_force_vertical || bindings.len() > 2;
 
                 // For `data` dialect always force vertical.
                 let should_force_vertical = should_force_vertical || self.dialect == Dialect::Data;
 
-                if should_force_vertical {
+                if self.is_compact(&bindings) {
+                    let flat = Layout::join(vec![
+                        Layout::item("{"),
+                        Layout::row(bindings.clone()),
+                        Layout::item("}"),
+                    ]);
+                    if bindings.is_empty() {
+                        return flat;
+                    }
+                    layouts.push(Layout::item("{"));
+                    layouts.push(Layout::indent(bindings));
+                    layouts.push(Layout::apply(Layout::item("}")));
+                    Layout::fit(flat, Layout::Stack(layouts))
+                } else if should_force_vertical {
                     layouts.push(Layout::item("{"));
                     layouts.push(Layout::indent(bindings));
                     layouts.push(Layout::apply(Layout::item("}")));
@@ -435,6 +1486,18 @@ impl<'a> Arranger<'a> {
                     Layout::join(layouts)
                 }
             }
+            Expr::Symbol(name) if name == "match" && is_match(self.list) => {
+                // The scrutinee is rendered inline, the arms are rendered
+                // vertically, one pattern/body pair per arm.
+                // safe to unwrap, checked by is_match.
+                let scrutinee = self.arrange_next().unwrap();
+                let (arms, _) = self.arrange_all_pairs();
+                let arms = arms.into_iter().map(match_arm).collect();
+                layouts.push(Layout::row(vec![Layout::item("(match"), scrutinee]));
+                layouts.push(Layout::indent(arms));
+                layouts.push(Layout::apply(Layout::item(")")));
+                Layout::Stack(layouts)
+            }
             Expr::Symbol(name) if name == "let" => {
                 // #todo add a more intuitive mechanism for mode, maybe a stack?
                 let old_mode = self.mode;
@@ -499,8 +1562,12 @@ impl<'a> Arranger<'a> {
                 // Function call.
                 layouts.push(Layout::item(format!("({head}")));
                 let (args, should_force_vertical) = self.arrange_all();
+                let should_force_vertical =
+                    should_force_vertical || self.has_line_break_after_head();
                 if !args.is_empty() {
-                    if should_force_vertical {
+                    if should_force_vertical && self.is_hanging(&args) {
+                        hanging_call(layouts.remove(0), args)
+                    } else if should_force_vertical {
                         layouts.push(Layout::indent(args));
                         layouts.push(Layout::apply(Layout::item(")")));
                         Layout::Stack(layouts)
@@ -518,35 +1585,275 @@ impl<'a> Arranger<'a> {
         }
     }
 
-    fn layout_from_expr(&self, expr: &Expr) -> Layout {
+    /// Returns an arranger for a nested list.
+    fn list_arranger<'l>(&self, exprs: &'l [Expr], end: Option<usize>) -> Arranger<'l> {
+        let mut list_arranger = Arranger::new(exprs, self.dialect);
+        list_arranger.depth = self.depth + 1;
+        list_arranger.end = end;
+        list_arranger.orphans = self.orphans.clone();
+        list_arranger.mode = self.mode;
+        list_arranger.options = self.options.clone();
+        list_arranger
+    }
+
+    /// Returns true if the arranged `if` has a nested `if` in the else
+    /// position, without comments.
+    fn is_if_chain(&self) -> bool {
+        let [_, _, _, else_expr] = self.list else {
+            return false;
+        };
+
+        if !is_chainable_if(self.list) || chainable_if(else_expr).is_none() {
+            return false;
+        }
+
+        // #insight Orphan comments within the chain are not supported.
+        !self.has_orphans()
+    }
+
+    /// Arranges an array of equal-length rows of scalars as a grid, with
+    /// aligned columns, if it does not fit on one line.
+    fn arrange_matrix(&self) -> Layout {
+        let rows = &self.list[1..];
+        let grid = Layout::Stack(vec![
+            Layout::item("["),
+            Layout::indent(format_matrix(rows)),
+            Layout::apply(Layout::item("]")),
+        ]);
+
+        // For `data` dialect the collections are vertical, unless compact.
+        if self.dialect == Dialect::Data && !self.options.compact_collections {
+            return grid;
+        }
+
+        let rows: Vec<Layout> = rows
+            .iter()
+            .map(|row| Layout::Item(self.format_flat(row)))
+            .collect();
+        let flat = Layout::join(vec![
+            Layout::item("["),
+            Layout::row(rows),
+            Layout::item("]"),
+        ]);
+        Layout::fit(flat, grid)
+    }
+
+    /// Arranges the items of an array, horizontally if possible.
+    fn arrange_array(&mut self) -> Layout {
+        let mut layouts = Vec::new();
+
+        // Try to format the array horizontally.
+        layouts.push(Layout::item("["));
+        // let (items, should_force_vertical) = self.arrange_all();
+        let (items, should_force_vertical) = self.arrange_all_array();
+
+        // #todo consider allowing horizontal for only one element.
+        // For `data` dialect always force vertical.
+        let should_force_vertical = should_force_vertical || self.dialect == Dialect::Data;
+
+        // #insight Filling takes precedence over the compact form, that
+        // falls back to one item per line.
+        if should_force_vertical && self.options.fill_arrays && is_fillable(&items) {
+            let flat = Layout::join(vec![
+                Layout::item("["),
+                Layout::row(items.clone()),
+                Layout::item("]"),
+            ]);
+            layouts.push(Layout::indent(vec![fill(items)]));
+            layouts.push(Layout::apply(Layout::item("]")));
+            Layout::fit(flat, Layout::Stack(layouts))
+        } else if self.is_compact(&items) && !items.is_empty() {
+            let flat = Layout::join(vec![
+                Layout::item("["),
+                Layout::row(items.clone()),
+                Layout::item("]"),
+            ]);
+            layouts.push(Layout::indent(items));
+            layouts.push(Layout::apply(Layout::item("]")));
+            Layout::fit(flat, Layout::Stack(layouts))
+        } else if !items.is_empty() {
+            if should_force_vertical {
+                layouts.push(Layout::indent(items));
+                layouts.push(Layout::apply(Layout::item("]")));
+                Layout::Stack(layouts)
+            } else {
+                match &items[0] {
+                    // Heuristic: if the array includes stacks, arrange
+                    // vertically.
+                    Layout::Stack(..) | Layout::Indent(..) => {
+                        layouts.push(Layout::indent(items));
+                        layouts.push(Layout::apply(Layout::item("]")));
+                        Layout::Stack(layouts)
+                    }
+                    _ => {
+                        layouts.push(Layout::row(items));
+                        layouts.push(Layout::item("]"));
+                        Layout::join(layouts)
+                    }
+                }
+            }
+        } else {
+            layouts.push(Layout::item("]"));
+            Layout::join(layouts)
+        }
+    }
+
+    /// Arranges an array of homogeneous Maps (records) as a table, if
+    /// requested in the data dialect. Returns None if the array is not a
+    /// table. Whether the table fits within the line size is decided when
+    /// rendering.
+    fn arrange_records(&self) -> Option<Layout> {
+        if self.dialect != Dialect::Data || !self.options.tabular_records || self.has_orphans() {
+            return None;
+        }
+
+        let rows = format_records(&self.list[1..], &self.options.map_key_order)?;
+
+        Some(Layout::Stack(vec![
+            Layout::item("["),
+            Layout::indent(rows),
+            Layout::apply(Layout::item("]")),
+        ]))
+    }
+
+    /// Returns true if the collection is arranged on a single line if it
+    /// fits, instead of the vertical form of the data dialect. The items
+    /// (or pairs) should be scalars, without comments.
+    fn is_compact(&self, items: &[Layout]) -> bool {
+        self.dialect == Dialect::Data
+            && self.options.compact_collections
+            && items.iter().all(|item| match item {
+                Layout::Item(s) => !is_comment(s),
+                Layout::Row(v, ..) => v
+                    .iter()
+                    .all(|item| matches!(item, Layout::Item(s) if !is_comment(s))),
+                _ => false,
+            })
+    }
+
+    /// Returns true if the arguments of a vertical call are aligned with the
+    /// first argument, if requested. The first argument should not be a
+    /// full-line comment.
+    fn is_hanging(&self, args: &[Layout]) -> bool {
+        // #insight A kept line break after the head is not pulled up, the
+        // output would be arranged differently when formatted again.
+        self.options.hanging_alignment
+            && args.len() > 1
+            && !matches!(&args[0], Layout::Item(s) if is_comment(s))
+            && !self.has_line_break_after_head()
+    }
+
+    /// Returns true if the first argument of the arranged list starts on a new
+    /// line in the source, and the source line breaks should be kept.
+    fn has_line_break_after_head(&self) -> bool {
+        if !self.options.keep_line_breaks {
+            return false;
+        }
+
+        let [head, arg, ..] = self.list else {
+            return false;
+        };
+
+        match (head.range(), arg.range()) {
+            (Some(head), Some(arg)) => arg.start.line > head.end.line,
+            _ => false,
+        }
+    }
+
+    /// Returns true if there are orphan comments before the end of the
+    /// arranged list.
+    fn has_orphans(&self) -> bool {
+        let orphans = self.orphans.borrow();
+        match (orphans.front(), self.end) {
+            (Some(comment), Some(end)) => comment.index < end,
+            (Some(..), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Arranges an else-if chain, nested `if` forms in the else position are
+    /// aligned with the outer `if`, instead of a staircase.
+    fn arrange_if_chain(&mut self) -> Layout {
+        let mut layouts = Vec::new();
+        let mut closing = String::new();
+        let mut link: Option<Arranger> = None;
+
+        loop {
+            let arranger = match &mut link {
+                Some(arranger) => arranger,
+                None => &mut *self,
+            };
+
+            // safe to unwrap, checked by is_chainable_if.
+            let condition = arranger.arrange_next().unwrap();
+            let then = arranger.arrange_next().unwrap();
+
+            layouts.push(Layout::row(vec![Layout::item("(if"), condition]));
+            closing.push(')');
+
+            let mut block = vec![then];
+
+            if let Some(expr) = arranger.next_expr() {
+                if let Some(exprs) = chainable_if(expr) {
+                    let end = expr.range().map(|range| range.end.index);
+                    let mut next = arranger.list_arranger(exprs, end);
+                    // Skip the `if` head.
+                    next.next_expr();
+                    layouts.push(Layout::indent(block));
+                    link = Some(next);
+                    continue;
+                }
+                block.push(arranger.layout_from_expr(expr));
+            }
+
+            layouts.push(Layout::indent(block));
+            break;
+        }
+
+        layouts.push(Layout::item(closing));
+
+        // #insight
+        // The links are aligned with the column of the first `(if`, it does
+        // not necessarily start the line.
+        let head = layouts.remove(0);
+        Layout::hang(head, layouts, 0)
+    }
+
+    fn layout_from_expr(&mut self, expr: &Expr) -> Layout {
+        let end = expr.range().map(|range| range.end.index);
+
         let (expr, _ann) = expr.extract();
 
+        let sort_keys = std::mem::take(&mut self.sort_next_keys);
+
         let layout = match expr {
-            Expr::Comment(s, _) => Layout::Item(s.clone()),
             Expr::TextSeparator => Layout::Separator, // #todo different impl!
-            Expr::String(s) => Layout::Item(format!("\"{}\"", escape_string(s))),
-            Expr::Symbol(s) => Layout::Item(s.clone()),
-            Expr::Int(n) => Layout::Item(n.to_string()),
-            // #insight `()` is the single instance of the Unit type `Nil`.
-            Expr::None => Layout::Item("()".to_string()),
-            Expr::Bool(b) => Layout::Item(b.to_string()),
-            Expr::Float(n) => Layout::Item(format_float(*n)),
-            // #todo Handle keypaths, don't desugar.
-            Expr::KeySymbol(s) => Layout::Item(format!(":{s}")),
-            Expr::Char(c) => Layout::Item(format!(r#"(Char "{c}")"#)),
             // #todo should handle Array?!
             Expr::List(exprs) => {
                 if exprs.is_empty() {
                     return Layout::Item("()".to_owned());
                 }
 
+                // #insight
+                // Deeply nested lists (e.g. machine-generated data) are
+                // formatted flat, to avoid a stack overflow.
+                if self.depth >= MAX_ARRANGE_DEPTH {
+                    return Layout::Item(self.format_flat(expr));
+                }
+
                 // #insight Recursive data structure, we recurse.
 
-                let mut list_arranger = Arranger::new(exprs, self.dialect);
-                list_arranger.mode = self.mode;
+                let mut list_arranger = self.list_arranger(exprs, end);
+                list_arranger.sort_keys = sort_keys;
                 list_arranger.arrange_list()
             }
-            _ => Layout::Item(expr.to_string()),
+            Expr::Comment(text, _) => self.comment_layout(text, false),
+            Expr::Annotation(..) => {
+                let annotation = expr.to_string();
+                self.sort_next_keys = sort_keys || annotation == SORT_KEYS_ANNOTATION;
+                Layout::Item(annotation)
+            }
+            _ => Layout::Item(format_atom(expr)),
         };
 
         // if let Some(ann) = ann {
@@ -1,4 +1,11 @@
+pub mod canonical;
+mod comments;
+pub mod compact;
+pub mod diff;
 pub mod layout;
 pub mod pretty;
+pub mod session;
+pub mod tolerant;
 pub mod types;
 mod util;
+mod writer;
@@ -1,14 +1,17 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{collections::VecDeque, fmt, io, rc::Rc};
 
 use tan::expr::Expr;
 
 use crate::{
-    layout::{Arranger, Layout},
-    types::Dialect,
-    util::{ensure_ends_with_empty_line, trim_separators},
+    comments::{orphan_comments, reflow_comment, Comment},
+    diff::unified_diff,
+    layout::{Arranger, Event, Layout},
+    types::{Dialect, Options},
+    util::{display_width, format_annotations, is_comment},
+    writer::{IoAdapter, LayoutWriter},
 };
 
-// #insight The formatter cannot err.
+// #insight The formatter cannot err, only the output sink can.
 
 // #todo align inline/side comments
 // #todo align vertical pairs (e.g. let)
@@ -20,33 +23,31 @@ use crate::{
 // #todo rename to `formatter.rs`
 // #todo optimize formatter to minimize diffs.
 // #todo try to maintain some empty separator lines.
-// #todo consider using tabs to indent?
 // #todo consider allowing absolutely no parameters for the formatter.
 // #todo idea: pre-process the input, add artificial separator-line annotations to maintain some of the user's separators?
 
-/// The default indentation size (char count)
-const DEFAULT_INDENT_SIZE: usize = 4;
-
-/// The default (target) line size (char count)
-const DEFAULT_LINE_SIZE: usize = 80;
-
 // const DEFAULT_DIALECT: &str = "code";
 
+/// Returns true if the text consists of closing delimiters, e.g. `))`.
+fn is_closing(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| matches!(c, ')' | ']' | '}'))
+}
+
 pub struct Formatter<'a> {
-    arranger: Arranger<'a>,
-    indent_size: usize,
-    #[allow(dead_code)]
-    line_size: usize,
+    exprs: &'a [Expr],
     // #todo consider different names, e.g. `flavor`?
-    // #todo use a builder pattern.
     pub dialect: Dialect,
+    options: Options,
     indent: usize,
-    #[allow(dead_code)]
-    col: usize,
+    // The part of the indentation used for alignment, rendered with spaces.
+    align: usize,
+    // Comments from the token stream, missing from the expressions.
+    orphans: VecDeque<Comment>,
+    // The source of the expressions.
+    source: Option<Rc<str>>,
 }
 
 // #todo introduce default constructor.
-// #todo introduce 'builder' api?
 
 impl<'a> Formatter<'a> {
     pub fn new(exprs: &'a [Expr]) -> Self {
@@ -55,99 +56,276 @@ impl<'a> Formatter<'a> {
 
     // #todo find a better name.
     pub fn for_dialect(exprs: &'a [Expr], dialect: Dialect) -> Self {
-        // #todo lazy-initialize the Arranger.
         Self {
-            arranger: Arranger::new(exprs, dialect),
+            exprs,
             indent: 0,
-            indent_size: DEFAULT_INDENT_SIZE,
-            line_size: DEFAULT_LINE_SIZE,
+            align: 0,
             dialect,
-            col: 0,
+            options: Options::for_dialect(dialect),
+            orphans: VecDeque::new(),
+            source: None,
         }
     }
 
-    fn apply_indent(&self, s: String, indent: usize) -> String {
-        format!("{ }{s}", " ".repeat(indent))
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
     }
 
-    fn format_annotations(&self, ann: &HashMap<String, Expr>) -> String {
-        if ann.is_empty() {
-            return "".to_string();
-        }
+    /// Reconciles the comments of the source that are missing from the
+    /// expressions, e.g. comments in positions the parser drops. The
+    /// expressions should be parsed from this source. The source layout is
+    /// preserved if requested, see `Options::preserve_layout`.
+    pub fn with_source(mut self, input: &str) -> Self {
+        self.orphans = orphan_comments(input, self.exprs);
+        self.source = Some(Rc::from(input));
+        self
+    }
 
-        // #todo temp solution (sorts annotations by key), ideally we want insertion order? or not?
+    // #todo automatically put `_` separators to numbers.
 
-        // Sort the annotations map, for stable formatting.
-        let ann = BTreeMap::from_iter(ann);
+    /// Writes the indentation of a line. If tabs are used, the indentation
+    /// levels are written as tabs and the alignment as spaces.
+    fn write_indent<W: fmt::Write>(&self, writer: &mut LayoutWriter<W>) {
+        if self.options.use_tabs {
+            let tab_width = self.options.tab_width.max(1);
+            let levels = self.indent - self.align;
+            writer.write_tabs(levels / tab_width);
+            writer.write_indent(levels % tab_width + self.align);
+        } else {
+            writer.write_indent(self.indent);
+        }
+    }
 
-        let mut output = String::new();
+    /// Returns true if all lines of the layout, rendered from the column at
+    /// the current indentation, fit within the line size.
+    fn fits_lines(&self, layout: &Layout, col: usize) -> bool {
+        let mut col = col;
+        let mut indent = self.indent;
+        let mut events = layout.events();
+
+        while let Some(event) = events.next() {
+            match event {
+                Event::Text(s) | Event::Separator(s) => {
+                    if is_comment(s) {
+                        return false;
+                    }
+                    col = match s.rsplit_once('\n') {
+                        Some((_, last)) => display_width(last),
+                        None => col + display_width(s),
+                    };
+                }
+                Event::Ann(ann) => col += display_width(&format_annotations(ann)),
+                Event::IndentStart(indent_size) => {
+                    indent += indent_size.unwrap_or(self.options.indent_width());
+                }
+                Event::IndentEnd(indent_size) => {
+                    indent -= indent_size.unwrap_or(self.options.indent_width());
+                }
+                Event::LineStart => col += indent,
+                Event::Fit(flat, broken) => {
+                    let fits = flat
+                        .flat_width()
+                        .is_some_and(|width| col + width <= self.options.line_size);
+                    events.push(if fits { flat } else { broken });
+                }
+                Event::Table(table, _) => events.push(table),
+                // #insight Hanging layouts are not expected in tables.
+                Event::Mark | Event::HangStart(..) | Event::HangEnd => return false,
+            }
 
-        for (key, value) in ann {
-            if key == "range" {
-                continue;
-            } else if let Expr::Bool(true) = value {
-                // Abbreviation for true booleans.
-                output.push_str(&format!("#{key} "));
-            } else {
-                // This case handles both (type X) and (key value) annotations.
-                // The value is the whole expression.
-                output.push_str(&format!("#{value} "));
+            if col > self.options.line_size {
+                return false;
             }
         }
 
-        output
+        true
     }
 
-    // #todo automatically put `_` separators to numbers.
+    fn writer<W: fmt::Write>(&self, sink: W) -> LayoutWriter<W> {
+        LayoutWriter::new(sink).with_tab_width(self.options.tab_width)
+    }
 
-    fn format_layout(&mut self, layout: &Layout) -> String {
-        match layout {
-            Layout::Item(s) => s.clone(),
-            Layout::Row(v, separator) => v
-                .iter()
-                .map(|l| self.format_layout(l))
-                .collect::<Vec<String>>()
-                .join(separator),
-            Layout::Stack(v) => v
-                .iter()
-                .map(|l| self.format_layout(l))
-                .collect::<Vec<String>>()
-                .join("\n"),
-            Layout::Indent(v, indent_size) => {
-                let indent_size = indent_size.unwrap_or(self.indent_size);
-                self.indent += indent_size;
-                let string = v
-                    .iter()
-                    .map(|l| {
-                        let string = self.format_layout(l);
-                        self.apply_indent(string, self.indent)
-                    })
-                    .collect::<Vec<String>>()
-                    .join("\n");
-                self.indent -= indent_size;
-                string
-            }
-            Layout::Apply(l) => {
-                let string = self.format_layout(l);
-                self.apply_indent(string, self.indent)
+    // #insight The layout is traversed without recursion, to support
+    // arbitrarily nested layouts.
+
+    fn format_layout<W: fmt::Write>(
+        &mut self,
+        layout: &Layout,
+        writer: &mut LayoutWriter<W>,
+    ) -> fmt::Result {
+        // #insight Full-line comments are reflowed, not inline comments.
+        let mut at_line_start = true;
+        // The indentation of the line is written, nothing else.
+        let mut at_indent = false;
+
+        // The marked columns and the indentation of the hanging blocks.
+        let mut marks = Vec::new();
+        let mut hangs = Vec::new();
+
+        let mut events = layout.events();
+
+        while let Some(event) = events.next() {
+            if matches!(
+                event,
+                Event::Text(..) | Event::Separator(..) | Event::Ann(..)
+            ) {
+                at_indent = false;
             }
-            Layout::Ann(ann, l) => {
-                let ann = self.format_annotations(ann);
-                let string = self.format_layout(l);
-                format!("{ann}{string}")
+
+            match event {
+                Event::Text(s)
+                    if self.options.reflow_comments && at_line_start && is_comment(s) =>
+                {
+                    let width = self.options.line_size.saturating_sub(self.indent);
+                    for (i, line) in reflow_comment(s, width).iter().enumerate() {
+                        if i > 0 {
+                            writer.write_str("\n")?;
+                            self.write_indent(writer);
+                        }
+                        writer.write_str(line)?;
+                    }
+                    at_line_start = false;
+                }
+                Event::Text(s) if self.options.stack_closing_delimiters && is_closing(s) => {
+                    writer.write_closing(s)?;
+                    at_line_start = false;
+                }
+                Event::Text(s) => {
+                    writer.write_str(s)?;
+                    at_line_start = at_line_start && s.trim().is_empty();
+                }
+                Event::Separator(s) => {
+                    writer.write_str(s)?;
+                    at_line_start = at_line_start || s.contains('\n');
+                }
+                // #insight An explicit indent size aligns, within an alignment
+                // everything is aligned.
+                Event::IndentStart(indent_size) => {
+                    let indent = indent_size.unwrap_or(self.options.indent_width());
+                    if indent_size.is_some() || self.align > 0 {
+                        self.align += indent;
+                    }
+                    self.indent += indent;
+                }
+                Event::IndentEnd(indent_size) => {
+                    let indent = indent_size.unwrap_or(self.options.indent_width());
+                    if indent_size.is_some() || self.align > 0 {
+                        self.align -= indent;
+                    }
+                    self.indent -= indent;
+                }
+                // #insight
+                // Consecutive line starts, e.g. an Indent in a hanging block,
+                // write the indentation of the innermost block.
+                Event::LineStart => {
+                    if at_indent {
+                        writer.clear_indent();
+                    }
+                    self.write_indent(writer);
+                    at_line_start = true;
+                    at_indent = true;
+                }
+                Event::Ann(ann) => {
+                    writer.write_str(&format_annotations(ann))?;
+                    at_line_start = false;
+                }
+                Event::Mark => marks.push(writer.col()),
+                Event::HangStart(offset, children) => {
+                    let col = marks.last().copied().unwrap_or(self.indent) + offset;
+                    // #insight Fall back to indentation, if the head is too long.
+                    let fits = children.iter().all(|child| {
+                        child
+                            .flat_width()
+                            .is_none_or(|width| col + width <= self.options.line_size)
+                    });
+                    let indent = if fits {
+                        col.saturating_sub(self.indent)
+                    } else {
+                        self.options.indent_width()
+                    };
+                    let align = if fits || self.align > 0 { indent } else { 0 };
+                    hangs.push((indent, align));
+                    self.indent += indent;
+                    self.align += align;
+                }
+                Event::HangEnd => {
+                    let (indent, align) = hangs.pop().unwrap_or_default();
+                    self.indent -= indent;
+                    self.align -= align;
+                    marks.pop();
+                }
+                Event::Fit(flat, broken) => {
+                    let fits = flat
+                        .flat_width()
+                        .is_some_and(|width| writer.col() + width <= self.options.line_size);
+                    events.push(if fits { flat } else { broken });
+                }
+                Event::Table(table, fallback) => {
+                    let fits = self.fits_lines(table, writer.col());
+                    events.push(if fits { table } else { fallback });
+                }
             }
-            Layout::Separator => "".to_owned(),
         }
+
+        Ok(())
+    }
+
+    /// Renders a Layout, without the final newline.
+    pub(crate) fn format_layout_to_string(&mut self, layout: &Layout) -> String {
+        let mut writer = self.writer(String::new());
+        // #insight Writing to a String cannot fail.
+        self.format_layout(layout, &mut writer).unwrap();
+        writer.into_inner()
     }
 
     /// Formats expressions into an aestheticall pleasing form.
     /// This is the standard textual representation of expressions.
-    pub fn format(mut self) -> String {
-        let layout = self.arranger.arrange();
-        // eprintln!("{:?}", &layout);
+    pub fn format(self) -> String {
+        let mut output = String::new();
+        // #insight Writing to a String cannot fail.
+        self.format_to(&mut output).unwrap();
+        output
+    }
+
+    /// Formats expressions, streams the output to a `fmt::Write` sink.
+    pub fn format_to<W: fmt::Write>(mut self, sink: W) -> fmt::Result {
+        let orphans = if self.options.strip_comments {
+            VecDeque::new()
+        } else {
+            std::mem::take(&mut self.orphans)
+        };
+        let mut arranger = Arranger::new(self.exprs, self.dialect)
+            .with_options(self.options.clone())
+            .with_orphan_comments(orphans);
+        if let Some(source) = self.source.take() {
+            arranger = arranger.with_source(source);
+        }
+        let layout = arranger.arrange();
         // dbg!(&layout);
-        let output = self.format_layout(&layout);
-        let output = trim_separators(&output);
-        ensure_ends_with_empty_line(&output)
+        let mut writer = self.writer(sink);
+        self.format_layout(&layout, &mut writer)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Formats expressions, streams the output to an `io::Write` sink. The
+    /// output is written in small fragments, consider using a buffered sink.
+    pub fn write_to<W: io::Write>(self, sink: W) -> io::Result<()> {
+        let mut adapter = IoAdapter::new(sink);
+        self.format_to(&mut adapter).map_err(|_| {
+            adapter
+                .error
+                .take()
+                .unwrap_or_else(|| io::Error::other("formatter error"))
+        })
+    }
+
+    /// Formats the expressions and compares the output with the original
+    /// source. Returns a unified diff if the formatter would change the
+    /// source, useful for check (CI) mode.
+    pub fn check(self, original: &str, path: &str, color: bool) -> Option<String> {
+        let output = self.with_source(original).format();
+        unified_diff(original, &output, path, color)
     }
 }
@@ -0,0 +1,207 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    rc::Rc,
+};
+
+use tan::{error::Error, expr::Expr};
+use tan_analysis::parsing::parse_string_for_analysis;
+
+use crate::{
+    comments::{orphan_comments, Comment},
+    layout::{is_inline_comment, Arranger, Layout},
+    pretty::Formatter,
+    types::{Dialect, Options},
+    writer::LayoutWriter,
+};
+
+// #todo consider caching across sessions, e.g. persist the cache.
+
+/// The arranged and rendered top-level form.
+struct CachedForm {
+    layout: Layout,
+    text: String,
+}
+
+/// A FormatterSession formats successive versions of a source, for example on
+/// every save in an editor. The Layout and the rendered text of each top-level
+/// form are cached, keyed by a hash of the form's source, so only the changed
+/// forms are re-arranged.
+pub struct FormatterSession {
+    dialect: Dialect,
+    options: Options,
+    cache: HashMap<u64, CachedForm>,
+    // The keys of the forms of the last formatted source, in order.
+    forms: Vec<u64>,
+    reused_count: usize,
+}
+
+impl Default for FormatterSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FormatterSession {
+    pub fn new() -> Self {
+        Self::for_dialect(Dialect::default())
+    }
+
+    pub fn for_dialect(dialect: Dialect) -> Self {
+        Self {
+            dialect,
+            options: Options::for_dialect(dialect),
+            cache: HashMap::new(),
+            forms: Vec::new(),
+            reused_count: 0,
+        }
+    }
+
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self.clear();
+        self
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    /// Changes the dialect, the cached forms are arranged for the previous
+    /// dialect and are discarded.
+    pub fn set_dialect(&mut self, dialect: Dialect) {
+        self.dialect = dialect;
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        self.cache.clear();
+        self.forms.clear();
+    }
+
+    /// The number of forms reused from the cache by the last format.
+    pub fn reused_count(&self) -> usize {
+        self.reused_count
+    }
+
+    /// The Layout of the last formatted source.
+    pub fn layout(&self) -> Layout {
+        Layout::Stack(
+            self.forms
+                .iter()
+                .map(|key| self.cache[key].layout.clone())
+                .collect(),
+        )
+    }
+
+    /// Parses and formats the input.
+    pub fn format(&mut self, input: &str) -> Result<String, Vec<Error>> {
+        let exprs = parse_string_for_analysis(input)?;
+        Ok(self.format_exprs(input, &exprs))
+    }
+
+    /// Formats expressions parsed from the input, reuses the cached results
+    /// of unchanged top-level forms.
+    pub fn format_exprs(&mut self, input: &str, exprs: &[Expr]) -> String {
+        let mut cache = HashMap::new();
+        let mut forms = Vec::new();
+        self.reused_count = 0;
+
+        let mut orphans = if self.options.strip_comments {
+            VecDeque::new()
+        } else {
+            orphan_comments(input, exprs)
+        };
+
+        let source: Rc<str> = Rc::from(input);
+
+        let mut start = 0;
+        // The source index of the end of the previous form.
+        let mut source_start = 0;
+
+        while start < exprs.len() {
+            // #insight A top-level form includes its inline comment.
+            let mut end = start + 1;
+            if end < exprs.len() && is_inline_comment(&exprs[end], &exprs[start]) {
+                end += 1;
+            }
+
+            let form = &exprs[start..end];
+
+            // #insight
+            // The source of a form includes the preceding orphan comments, the
+            // last form also includes the orphan comments at the end.
+            let source_end = if end < exprs.len() {
+                form[form.len() - 1]
+                    .range()
+                    .map_or(source_start, |r| r.end.index)
+            } else {
+                input.len()
+            };
+            let mut form_orphans = VecDeque::new();
+            while let Some(comment) = orphans.pop_front() {
+                if comment.index >= source_end {
+                    orphans.push_front(comment);
+                    break;
+                }
+                form_orphans.push_back(comment);
+            }
+
+            let key = self.form_key(input, source_start, source_end);
+
+            let cached = if let Some(cached) = self.cache.remove(&key) {
+                self.reused_count += 1;
+                cached
+            } else if let Some(cached) = cache.remove(&key) {
+                // A duplicate of a form in the current source.
+                cached
+            } else {
+                self.arrange_form(form, form_orphans, source.clone())
+            };
+
+            forms.push(key);
+            cache.insert(key, cached);
+
+            start = end;
+            source_start = source_end;
+        }
+
+        let mut writer = LayoutWriter::new(String::new());
+        for (i, key) in forms.iter().enumerate() {
+            // #insight Writing to a String cannot fail.
+            if i > 0 {
+                writer.write_str("\n").unwrap();
+            }
+            writer.write_str(&cache[key].text).unwrap();
+        }
+
+        // #insight Forms that are not used in this version are evicted.
+        self.cache = cache;
+        self.forms = forms;
+
+        writer.finish().unwrap()
+    }
+
+    fn form_key(&self, input: &str, start: usize, end: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        input.get(start..end).unwrap_or_default().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn arrange_form(
+        &self,
+        form: &[Expr],
+        orphans: VecDeque<Comment>,
+        source: Rc<str>,
+    ) -> CachedForm {
+        let layout = Arranger::new(form, self.dialect)
+            .with_options(self.options.clone())
+            .with_orphan_comments(orphans)
+            .with_source(source)
+            .arrange();
+        let text = Formatter::for_dialect(form, self.dialect)
+            .with_options(self.options.clone())
+            .format_layout_to_string(&layout);
+        CachedForm { layout, text }
+    }
+}
@@ -0,0 +1,298 @@
+use tan::{
+    api::lex_string,
+    error::Error,
+    lexer::token::{Token, TokenKind},
+};
+use tan_analysis::parsing::parse_string_for_analysis;
+
+use crate::{
+    pretty::Formatter,
+    types::{Dialect, Options},
+};
+
+// #todo recover from unbalanced delimiters inside a top-level form, e.g. keep
+// formatting the nested forms that are balanced.
+
+/// A region of the source that contains top-level forms.
+#[derive(Debug)]
+struct Segment {
+    // The source index of the start of the region.
+    start: usize,
+    // The source index of the end of the region.
+    end: usize,
+    // The region is preceded by an empty line.
+    separated: bool,
+    // The region cannot be parsed, it's copied verbatim.
+    broken: bool,
+}
+
+/// Splits the tokens into top-level segments, tracks the delimiter depth to
+/// find unbalanced delimiters.
+fn segments(tokens: &[Token]) -> Vec<Segment> {
+    let mut segments = Vec::new();
+
+    let mut depth = 0;
+    let mut start = None;
+    let mut last_end = 0;
+    // An empty line precedes the current token.
+    let mut separated = false;
+    // An empty line precedes the current segment.
+    let mut segment_separated = false;
+
+    for token in tokens {
+        let range = token.range();
+
+        // #insight
+        // An empty line within an unclosed form may precede a top-level form,
+        // it's tracked at any depth.
+        if matches!(token.kind(), TokenKind::MultiLineWhitespace) {
+            separated = true;
+            continue;
+        }
+        let token_separated = std::mem::take(&mut separated);
+
+        match token.kind() {
+            TokenKind::LeftParen | TokenKind::LeftBracket | TokenKind::LeftBrace => {
+                // #insight
+                // A form that starts at the first column is assumed to be a
+                // top-level form, the previous form is left unclosed.
+                if depth > 0 && range.start.col == 0 {
+                    segments.push(Segment {
+                        start: start.take().unwrap_or(range.start.index),
+                        end: last_end,
+                        separated: segment_separated,
+                        broken: true,
+                    });
+                    depth = 0;
+                }
+                if start.is_none() {
+                    start = Some(range.start.index);
+                    segment_separated = token_separated;
+                }
+                depth += 1;
+            }
+            TokenKind::RightParen | TokenKind::RightBracket | TokenKind::RightBrace => {
+                if depth == 0 {
+                    // An unbalanced closing delimiter.
+                    segments.push(Segment {
+                        start: range.start.index,
+                        end: range.end.index,
+                        separated: token_separated,
+                        broken: true,
+                    });
+                    last_end = range.end.index;
+                    continue;
+                }
+                depth -= 1;
+            }
+            _ => {
+                if start.is_none() {
+                    start = Some(range.start.index);
+                    segment_separated = token_separated;
+                }
+            }
+        }
+
+        last_end = range.end.index;
+
+        // #insight A quote or unquote prefix belongs to the next form.
+        if depth == 0 && !matches!(token.kind(), TokenKind::Quote | TokenKind::Unquote) {
+            if let Some(start) = start.take() {
+                segments.push(Segment {
+                    start,
+                    end: last_end,
+                    separated: segment_separated,
+                    broken: false,
+                });
+            }
+        }
+    }
+
+    if let Some(start) = start {
+        // An unclosed form at the end of the source.
+        segments.push(Segment {
+            start,
+            end: last_end,
+            separated: segment_separated,
+            broken: true,
+        });
+    }
+
+    segments
+}
+
+/// The TolerantFormatter formats sources with syntax errors, for example
+/// while a file is edited. Every top-level form that parses is formatted,
+/// the unparseable regions are copied verbatim.
+pub struct TolerantFormatter<'a> {
+    input: &'a str,
+    pub dialect: Dialect,
+    options: Options,
+}
+
+impl<'a> TolerantFormatter<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self::for_dialect(input, Dialect::default())
+    }
+
+    pub fn for_dialect(input: &'a str, dialect: Dialect) -> Self {
+        Self {
+            input,
+            dialect,
+            options: Options::for_dialect(dialect),
+        }
+    }
+
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    fn format_source(&self, source: &str) -> Option<String> {
+        let exprs = parse_string_for_analysis(source).ok()?;
+        let output = Formatter::for_dialect(&exprs, self.dialect)
+            .with_options(self.options.clone())
+            .with_source(source)
+            .format();
+        Some(output)
+    }
+
+    fn format_segments(&self, input: &str, output: &mut String) {
+        let tokens = match lex_string(input) {
+            Ok(tokens) => tokens,
+            Err(errors) => {
+                // #insight
+                // The source cannot be tokenized after the lexer error, the
+                // source before the line of the error is formatted.
+                let error_start = errors
+                    .first()
+                    .and_then(|error| error.range())
+                    .map(|range| range.start.index)
+                    .unwrap_or(0);
+                let line_start = input[..error_start].rfind('\n').map_or(0, |i| i + 1);
+                if line_start > 0 {
+                    self.format_segments(&input[..line_start], output);
+                }
+                output.push_str(input[line_start..].trim_end());
+                output.push('\n');
+                return;
+            }
+        };
+
+        let mut segments = segments(&tokens).into_iter().peekable();
+        let mut last_end = 0;
+
+        while let Some(segment) = segments.next() {
+            if segment.separated && !output.is_empty() {
+                output.push('\n');
+            }
+
+            let gap = &input[last_end..segment.start];
+
+            if !segment.broken {
+                // Consecutive parseable segments are formatted together, to
+                // retain inline comments and separators.
+                let start = segment.start;
+                let mut end = segment.end;
+                while let Some(next) = segments.next_if(|s| !s.broken) {
+                    end = next.end;
+                }
+
+                let source = &input[start..end];
+                last_end = end;
+
+                if let Some(formatted) = self.format_source(source) {
+                    continue_line(output, gap);
+                    output.push_str(&formatted);
+                    continue;
+                }
+
+                // #insight A chunk with a non-structural error, format the
+                // segments one by one.
+                let mut last_end = start;
+                for segment in segments_of(&tokens, start, end) {
+                    if segment.separated && !output.is_empty() {
+                        output.push('\n');
+                    }
+                    let gap = &input[last_end..segment.start];
+                    continue_line(output, gap);
+                    let source = &input[segment.start..segment.end];
+                    match self.format_source(source) {
+                        Some(formatted) => output.push_str(&formatted),
+                        None => {
+                            let start = line_start(gap, last_end, segment.start);
+                            output.push_str(&input[start..segment.end]);
+                            output.push('\n');
+                        }
+                    }
+                    last_end = segment.end;
+                }
+                continue;
+            }
+
+            continue_line(output, gap);
+            let start = line_start(gap, last_end, segment.start);
+            last_end = segment.end;
+            output.push_str(&input[start..segment.end]);
+            output.push('\n');
+        }
+    }
+
+    /// Formats the input, returns the output and the parse errors.
+    pub fn format(self) -> (String, Vec<Error>) {
+        match parse_string_for_analysis(self.input) {
+            Ok(exprs) => {
+                let output = Formatter::for_dialect(&exprs, self.dialect)
+                    .with_options(self.options.clone())
+                    .with_source(self.input)
+                    .format();
+                (output, Vec::new())
+            }
+            Err(errors) => {
+                let mut output = String::new();
+                self.format_segments(self.input, &mut output);
+                (output, errors)
+            }
+        }
+    }
+}
+
+/// Continues the last line of the output, if the segment starts on the line
+/// where the previous segment ends, e.g. a stray closing delimiter. The gap
+/// between the segments is copied verbatim.
+fn continue_line(output: &mut String, gap: &str) {
+    if !gap.contains('\n') && output.ends_with('\n') {
+        output.pop();
+        output.push_str(gap);
+    }
+}
+
+/// Returns the source index where a verbatim copy of a segment starts. A
+/// segment that starts a line is copied with its indentation.
+fn line_start(gap: &str, gap_start: usize, start: usize) -> usize {
+    gap.rfind('\n').map_or(start, |i| gap_start + i + 1)
+}
+
+/// Returns the segments within the given source region.
+fn segments_of(tokens: &[Token], start: usize, end: usize) -> Vec<Segment> {
+    // #insight The tokens are ordered by position.
+    let i = tokens.partition_point(|token| token.range().start.index < start);
+    let j = tokens.partition_point(|token| token.range().end.index <= end);
+    segments(&tokens[i..j])
+}
+
+#[cfg(test)]
+mod tests {
+    use tan::api::lex_string;
+
+    use crate::tolerant::segments;
+
+    #[test]
+    fn segments_keep_prefixes_with_the_next_form() {
+        let tokens = lex_string("'(a b) $c (d)").unwrap();
+        let segments = segments(&tokens);
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!((segments[1].start, segments[1].end), (7, 9));
+    }
+}
@@ -1,18 +1,151 @@
 // #todo Find a better name than Dialect, maybe Flavor?
 // #todo Could we make dialects/formatting pluggable?
 
+/// The default indentation size (char count)
+const DEFAULT_INDENT_SIZE: usize = 4;
+
+/// The default (target) line size (char count)
+const DEFAULT_LINE_SIZE: usize = 80;
+
+/// The default display width of a tab (char count)
+pub(crate) const DEFAULT_TAB_WIDTH: usize = 4;
+
 /// The dialect of the source Tan. The formatter offer customized formatting for
 /// different Dialects.
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
 pub enum Dialect {
+    #[default]
     Code,
     Data,
     Html,
     Css,
 }
 
-impl Default for Dialect {
+/// The order of the keys of `Map` expressions. Comments stay attached to
+/// their pair. An individual Map can request alphabetical order with a
+/// `#sort-keys` annotation.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum MapKeyOrder {
+    /// Preserve the source order.
+    #[default]
+    Source,
+    Alphabetical,
+    /// The listed keys come first, in the given order, the rest of the keys
+    /// follow in alphabetical order.
+    Priority(Vec<String>),
+}
+
+/// The comment markers by position, used to normalize comments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommentMarkers {
+    /// The marker of full-line comments at the top level.
+    pub top_level: String,
+    /// The marker of full-line comments within expressions.
+    pub nested: String,
+    /// The marker of inline (trailing) comments.
+    pub inline: String,
+}
+
+impl Default for CommentMarkers {
+    fn default() -> Self {
+        Self {
+            top_level: ";".to_string(),
+            nested: ";".to_string(),
+            inline: ";".to_string(),
+        }
+    }
+}
+
+/// Options to customize the arrangement and rendering of the formatted output.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The indentation size (char count).
+    pub indent_size: usize,
+    /// The (target) line size (char count).
+    pub line_size: usize,
+    /// Strip comments and doc annotations from the output.
+    pub strip_comments: bool,
+    /// The order of the keys of `Map` expressions. The order applies to all
+    /// the Maps of the formatted source, there is no per-dialect order.
+    pub map_key_order: MapKeyOrder,
+    /// Normalize the comment markers and the spacing of comments, if set.
+    pub comment_markers: Option<CommentMarkers>,
+    /// Pack the items of long arrays of atoms into lines that fit within the
+    /// line size, instead of one item per line.
+    pub fill_arrays: bool,
+    /// In the data dialect, render arrays of Maps with the same keys (records)
+    /// as a table, one record per line, with aligned values.
+    pub tabular_records: bool,
+    /// In the data dialect, render empty collections as `[]` or `{}`, and
+    /// collections of scalars on a single line if they fit.
+    pub compact_collections: bool,
+    /// Keep a call multi-line if its first argument starts on a new line in
+    /// the source, e.g. a builder-style call with one argument per line.
+    pub keep_line_breaks: bool,
+    /// Keep the source layout of the top-level forms that satisfy the layout
+    /// constraints (width, indentation), only the violating forms are
+    /// formatted. Requires the source, see `Formatter::with_source`.
+    pub preserve_layout: bool,
+    /// Gather the closing delimiters of vertical forms onto the last line of
+    /// the body, as in traditional Lisp style.
+    pub stack_closing_delimiters: bool,
+    /// Align the arguments of vertical calls with the first argument, instead
+    /// of a fixed indentation, as in classic Lisp style.
+    pub hanging_alignment: bool,
+    /// Arrange an `if` with a single branch pair on one line, if it fits.
+    pub collapse_short_ifs: bool,
+    /// Reflow consecutive full-line comments into paragraphs that fit within
+    /// the line size.
+    pub reflow_comments: bool,
+    /// Indent with tabs, one tab per indentation level. Alignment (e.g. of
+    /// `let` bindings) still uses spaces (smart tabs).
+    pub use_tabs: bool,
+    /// The display width of a tab (char count), used in width calculations.
+    pub tab_width: usize,
+}
+
+impl Default for Options {
     fn default() -> Self {
-        Self::Code
+        Self {
+            indent_size: DEFAULT_INDENT_SIZE,
+            line_size: DEFAULT_LINE_SIZE,
+            strip_comments: false,
+            map_key_order: MapKeyOrder::default(),
+            comment_markers: None,
+            fill_arrays: false,
+            tabular_records: false,
+            compact_collections: false,
+            keep_line_breaks: false,
+            preserve_layout: false,
+            stack_closing_delimiters: false,
+            hanging_alignment: false,
+            collapse_short_ifs: false,
+            reflow_comments: false,
+            use_tabs: false,
+            tab_width: DEFAULT_TAB_WIDTH,
+        }
+    }
+}
+
+impl Options {
+    /// The width of an indentation level, a tab if tabs are used. A tab is at
+    /// least one column wide.
+    pub fn indent_width(&self) -> usize {
+        if self.use_tabs {
+            self.tab_width.max(1)
+        } else {
+            self.indent_size
+        }
+    }
+
+    /// The default options of the dialect.
+    pub fn for_dialect(dialect: Dialect) -> Self {
+        match dialect {
+            Dialect::Data => Self {
+                compact_collections: true,
+                ..Default::default()
+            },
+            _ => Self::default(),
+        }
     }
 }
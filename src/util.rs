@@ -1,38 +1,67 @@
-use std::sync::OnceLock;
+use std::collections::{BTreeMap, HashMap};
 
-use regex::Regex;
+use tan::expr::Expr;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-static TRAILING_EOL_REGEX: OnceLock<Regex> = OnceLock::new();
+pub fn escape_string(input: &str) -> String {
+    input
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('"', "\\\"")
+}
+
+pub fn format_annotations(ann: &HashMap<String, Expr>) -> String {
+    if ann.is_empty() {
+        return "".to_string();
+    }
 
-pub fn ensure_ends_with_empty_line(input: &str) -> String {
-    let regex = TRAILING_EOL_REGEX.get_or_init(|| Regex::new(r"\n+$").unwrap());
+    // #todo temp solution (sorts annotations by key), ideally we want insertion order? or not?
 
-    let mut output = regex.replace(input, "\n").to_string();
+    // Sort the annotations map, for stable formatting.
+    let ann = BTreeMap::from_iter(ann);
 
-    if !output.ends_with('\n') {
-        output.push('\n');
+    let mut output = String::new();
+
+    for (key, value) in ann {
+        if key == "range" {
+            continue;
+        } else if let Expr::Bool(true) = value {
+            // Abbreviation for true booleans.
+            output.push_str(&format!("#{key} "));
+        } else {
+            // This case handles both (type X) and (key value) annotations.
+            // The value is the whole expression.
+            output.push_str(&format!("#{value} "));
+        }
     }
 
     output
 }
 
-static SEPARATOR_REGEX: OnceLock<Regex> = OnceLock::new();
+/// Returns true if the expression is a doc annotation, e.g. `#(doc "...")`.
+pub fn is_doc_annotation(expr: &Expr) -> bool {
+    matches!(expr.unpack(), Expr::Annotation(..)) && expr.to_string().starts_with("#(doc ")
+}
 
-pub fn trim_separators(input: &str) -> String {
-    let regex = SEPARATOR_REGEX.get_or_init(|| Regex::new(r"[ \t]+\n").unwrap());
-    regex.replace_all(input, "\n").to_string()
+// #insight
+// The display width is measured per grapheme cluster, e.g. a letter with
+// combining accents or an emoji sequence takes at most two columns.
+
+/// Returns the display width of the text (column count), wide characters (e.g.
+/// CJK) take two columns.
+pub fn display_width(s: &str) -> usize {
+    s.graphemes(true).map(|g| g.width().min(2)).sum()
 }
 
-pub fn escape_string(input: &str) -> String {
-    input
-        .replace('\n', "\\n")
-        .replace('\t', "\\t")
-        .replace('"', "\\\"")
+/// Returns true if the (rendered) item is a comment.
+pub fn is_comment(item: &str) -> bool {
+    item.starts_with(';')
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::util::escape_string;
+    use crate::util::{display_width, escape_string};
 
     #[test]
     fn escape_string_works() {
@@ -46,4 +75,13 @@ mod tests {
 
         assert_eq!(escaped, "first\\nsecond");
     }
+
+    #[test]
+    fn display_width_counts_columns() {
+        assert_eq!(display_width("hello"), 5);
+        assert_eq!(display_width("καλημέρα"), 8);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("👍🏽"), 2);
+    }
 }
@@ -0,0 +1,189 @@
+use std::{fmt, io};
+
+use crate::{types::DEFAULT_TAB_WIDTH, util::display_width};
+
+// #insight
+// The writer defers whitespace until the next visible fragment is written, so
+// the output never contains trailing whitespace and there is no need for a
+// final pass over the whole output.
+
+/// A LayoutWriter writes the rendered fragments of a Layout to a `fmt::Write`
+/// sink, without intermediate allocations.
+pub struct LayoutWriter<W: fmt::Write> {
+    sink: W,
+    // Spaces and tabs, written before the next visible fragment.
+    pending_whitespace: String,
+    // Newlines, written before the next visible fragment.
+    pending_newlines: usize,
+    // The column after the last visible fragment.
+    col: usize,
+    // The current line contains a comment.
+    in_comment: bool,
+    // The display width of a tab.
+    tab_width: usize,
+}
+
+/// Returns the column after writing the text at the given column, tabs advance
+/// to the next tab stop.
+fn advance(col: usize, s: &str, tab_width: usize) -> usize {
+    s.chars().fold(col, |col, c| {
+        if c == '\t' {
+            (col / tab_width + 1) * tab_width
+        } else {
+            col + 1
+        }
+    })
+}
+
+impl<W: fmt::Write> LayoutWriter<W> {
+    pub fn new(sink: W) -> Self {
+        Self {
+            sink,
+            pending_whitespace: String::new(),
+            pending_newlines: 0,
+            col: 0,
+            in_comment: false,
+            tab_width: DEFAULT_TAB_WIDTH,
+        }
+    }
+
+    pub fn with_tab_width(mut self, tab_width: usize) -> Self {
+        self.tab_width = tab_width.max(1);
+        self
+    }
+
+    /// The column of the next visible fragment, including pending whitespace.
+    pub fn col(&self) -> usize {
+        let col = if self.pending_newlines > 0 {
+            0
+        } else {
+            self.col
+        };
+        advance(col, &self.pending_whitespace, self.tab_width)
+    }
+
+    /// Writes indentation (or alignment) spaces.
+    pub fn write_indent(&mut self, indent: usize) {
+        self.pending_whitespace
+            .extend(std::iter::repeat_n(' ', indent));
+    }
+
+    /// Discards the pending indentation, e.g. to replace it with the
+    /// indentation of a nested block.
+    pub fn clear_indent(&mut self) {
+        self.pending_whitespace.clear();
+    }
+
+    /// Writes indentation tabs.
+    pub fn write_tabs(&mut self, count: usize) {
+        self.pending_whitespace
+            .extend(std::iter::repeat_n('\t', count));
+    }
+
+    fn write_visible(&mut self, s: &str) -> fmt::Result {
+        if s.is_empty() {
+            return Ok(());
+        }
+
+        if self.pending_newlines > 0 {
+            for _ in 0..self.pending_newlines {
+                self.sink.write_char('\n')?;
+            }
+            self.pending_newlines = 0;
+            self.col = 0;
+            self.in_comment = false;
+        }
+
+        // #insight A string that contains ` ;` is also detected, it's safe.
+        self.in_comment = self.in_comment || s.starts_with(';');
+
+        self.sink.write_str(&self.pending_whitespace)?;
+        self.col = advance(self.col, &self.pending_whitespace, self.tab_width) + display_width(s);
+        self.pending_whitespace.clear();
+
+        self.sink.write_str(s)
+    }
+
+    /// Writes a fragment, the fragment may contain whitespace and newlines.
+    pub fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+
+        for (i, c) in s.char_indices() {
+            if c == ' ' || c == '\t' || c == '\n' {
+                self.write_visible(&s[start..i])?;
+                start = i + 1;
+                if c == '\n' {
+                    // #insight Trailing whitespace is dropped.
+                    self.pending_whitespace.clear();
+                    self.pending_newlines += 1;
+                } else {
+                    self.pending_whitespace.push(c);
+                }
+            }
+        }
+
+        self.write_visible(&s[start..])
+    }
+
+    /// Writes closing delimiters at the end of the previous line, unless the
+    /// line ends with a comment.
+    pub fn write_closing(&mut self, s: &str) -> fmt::Result {
+        if !self.in_comment {
+            self.pending_newlines = 0;
+            self.pending_whitespace.clear();
+        }
+        self.write_str(s)
+    }
+
+    /// Returns the sink, pending whitespace and newlines are dropped.
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+
+    /// Terminates the output with exactly one newline, returns the sink.
+    pub fn finish(mut self) -> Result<W, fmt::Error> {
+        self.sink.write_char('\n')?;
+        Ok(self.sink)
+    }
+}
+
+/// Adapts an `io::Write` sink to `fmt::Write`, retains the underlying io
+/// error.
+pub struct IoAdapter<W: io::Write> {
+    inner: W,
+    pub error: Option<io::Error>,
+}
+
+impl<W: io::Write> IoAdapter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, error: None }
+    }
+}
+
+impl<W: io::Write> fmt::Write for IoAdapter<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|error| {
+            self.error = Some(error);
+            fmt::Error
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::writer::LayoutWriter;
+
+    #[test]
+    fn layout_writer_never_emits_trailing_whitespace() {
+        let mut writer = LayoutWriter::new(String::new());
+        writer.write_str("(do ").unwrap();
+        writer.write_str("\n\n").unwrap();
+        writer.write_indent(4);
+        writer.write_str("(let a 1) \n").unwrap();
+        writer.write_indent(4);
+        writer.write_str("\n)\n\n").unwrap();
+        let output = writer.finish().unwrap();
+
+        assert_eq!(output, "(do\n\n    (let a 1)\n\n)\n");
+    }
+}
@@ -0,0 +1,37 @@
+use common::parse_file;
+use tan_formatting::canonical::CanonicalFormatter;
+
+mod common;
+
+fn format_canonical_file(filename: &str) -> String {
+    let exprs = parse_file(filename).unwrap();
+    CanonicalFormatter::new(&exprs).format()
+}
+
+#[test]
+pub fn format_canonical_sorts_map_keys() {
+    let output = format_canonical_file("data-2.tan");
+    let expected_output = r#"[{:name "0" :value 0} {:name "px" :value 1} {:name "0.5" :value 2} {:name "1" :value 4}]"#;
+
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn format_canonical_is_independent_of_source_layout() {
+    assert_eq!(
+        format_canonical_file("data.tan"),
+        format_canonical_file("data.pretty.tan")
+    );
+    assert_eq!(
+        format_canonical_file("fibalike.tan"),
+        format_canonical_file("fibalike.pretty.tan")
+    );
+}
+
+#[test]
+pub fn format_canonical_handles_stripped_comment_blocks() {
+    let output = format_canonical_file("commented-stubs.tan");
+    let expected_output = "(Func [x])\n(if c)\n(for [x xs])";
+
+    assert_eq!(output, expected_output);
+}
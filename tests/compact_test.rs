@@ -0,0 +1,48 @@
+use common::{parse_file, read_file};
+use tan_formatting::compact::CompactFormatter;
+
+mod common;
+
+#[test]
+pub fn format_compact_handles_code_input() {
+    let exprs = parse_file("simple-example.tan").unwrap();
+    let formatter = CompactFormatter::new(&exprs).strip_comments();
+
+    let output = formatter.format();
+    let expected_output = read_file("simple-example.compact.tan");
+
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn format_compact_handles_stripped_comment_blocks() {
+    let exprs = parse_file("commented-stubs.tan").unwrap();
+    let formatter = CompactFormatter::new(&exprs).strip_comments();
+
+    let output = formatter.format();
+    let expected_output = read_file("commented-stubs.compact.tan");
+
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn format_compact_retains_comments() {
+    let exprs = parse_file("inline-comments.tan").unwrap();
+    let formatter = CompactFormatter::new(&exprs);
+
+    let output = formatter.format();
+    let expected_output = read_file("inline-comments.compact.tan");
+
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn format_compact_drops_grid_alignment() {
+    let exprs = parse_file("array-matrix.tan").unwrap();
+    let formatter = CompactFormatter::new(&exprs);
+
+    let output = formatter.format();
+    let expected_output = read_file("array-matrix.compact.tan");
+
+    assert_eq!(output, expected_output);
+}
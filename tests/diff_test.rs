@@ -0,0 +1,38 @@
+use common::{parse_file, read_file};
+use tan_formatting::{pretty::Formatter, types::Dialect};
+
+mod common;
+
+#[test]
+pub fn check_returns_unified_diff() {
+    let input = read_file("code.tan");
+    let exprs = parse_file("code.tan").unwrap();
+    let formatter = Formatter::for_dialect(&exprs, Dialect::Code);
+
+    let diff = formatter.check(&input, "code.tan", false).unwrap();
+
+    let expected_diff = r#"--- a/code.tan
++++ b/code.tan
+@@ -1,6 +1,5 @@
+-(print
+-    "hello world"
+-)
++(print "hello world")
+ 
+ (let a 1.0
+-b 2)
++     b 2
++)
+"#;
+
+    assert_eq!(diff, expected_diff);
+}
+
+#[test]
+pub fn check_returns_none_for_formatted_input() {
+    let input = read_file("code.pretty.tan");
+    let exprs = parse_file("code.pretty.tan").unwrap();
+    let formatter = Formatter::for_dialect(&exprs, Dialect::Code);
+
+    assert!(formatter.check(&input, "code.pretty.tan", false).is_none());
+}
@@ -1,5 +1,10 @@
 use common::parse_file;
-use tan_formatting::{pretty::Formatter, types::Dialect};
+use tan::expr::Expr;
+use tan_analysis::parsing::parse_string_for_analysis;
+use tan_formatting::{
+    pretty::Formatter,
+    types::{CommentMarkers, Dialect, MapKeyOrder, Options},
+};
 
 use crate::common::read_file;
 
@@ -18,6 +23,16 @@ fn test_fixture(name: &str, dialect: Dialect) {
     assert_eq!(output, expected_output);
 }
 
+fn test_fixture_with_options(name: &str, variant: &str, dialect: Dialect, options: Options) {
+    let exprs = parse_file(&format!("{name}.tan")).unwrap();
+    let formatter = Formatter::for_dialect(&exprs, dialect).with_options(options);
+
+    let output = formatter.format();
+    let expected_output = read_file(&format!("{name}.{variant}.pretty.tan"));
+
+    assert_eq!(output, expected_output);
+}
+
 fn test_code_fixture(name: &str) {
     test_fixture(name, Dialect::Code)
 }
@@ -91,6 +106,175 @@ pub fn format_pretty_makes_unquoting_uniform() {
     test_code_fixture("unquote");
 }
 
+#[test]
+pub fn format_pretty_stacks_closing_delimiters() {
+    let options = Options {
+        stack_closing_delimiters: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("fibalike", "stacked", Dialect::Code, options.clone());
+    test_fixture_with_options("dangling-comments", "stacked", Dialect::Code, options);
+}
+
+#[test]
+pub fn format_pretty_preserves_conforming_source_layout() {
+    let input = read_file("preserve.tan");
+    let exprs = parse_file("preserve.tan").unwrap();
+    let options = Options {
+        preserve_layout: true,
+        ..Default::default()
+    };
+    let output = Formatter::new(&exprs)
+        .with_options(options)
+        .with_source(&input)
+        .format();
+    let expected_output = read_file("preserve.preserve.pretty.tan");
+
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn format_pretty_preserves_layout_with_zero_indent_size() {
+    let input = read_file("preserve.tan");
+    let exprs = parse_file("preserve.tan").unwrap();
+    let options = Options {
+        preserve_layout: true,
+        indent_size: 0,
+        ..Default::default()
+    };
+    let output = Formatter::new(&exprs)
+        .with_options(options)
+        .with_source(&input)
+        .format();
+
+    // An indented line is not conforming, without indentation levels.
+    assert!(output.contains("(let a   1) ; kept as-is"));
+    assert!(output.contains("(writeln \"hello\" \"world\")"));
+}
+
+#[test]
+pub fn format_pretty_keeps_intentional_line_breaks() {
+    let options = Options {
+        keep_line_breaks: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("line-breaks", "keep", Dialect::Code, options);
+}
+
+#[test]
+pub fn format_pretty_renders_compact_data_collections() {
+    test_data_fixture("data-compact");
+}
+
+#[test]
+pub fn format_pretty_renders_records_as_table() {
+    let options = Options {
+        tabular_records: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("records", "tabular", Dialect::Data, options);
+}
+
+#[test]
+pub fn format_pretty_aligns_by_display_width() {
+    let options = Options {
+        tabular_records: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("records-unicode", "tabular", Dialect::Data, options);
+}
+
+#[test]
+pub fn format_pretty_aligns_matrix_columns() {
+    test_code_fixture("array-matrix");
+}
+
+#[test]
+pub fn format_pretty_fills_long_arrays() {
+    let options = Options {
+        fill_arrays: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("array-fill", "fill", Dialect::Code, options);
+}
+
+#[test]
+pub fn format_pretty_fills_long_data_arrays() {
+    let options = Options {
+        fill_arrays: true,
+        ..Options::for_dialect(Dialect::Data)
+    };
+    test_fixture_with_options("data-fill", "fill", Dialect::Data, options);
+}
+
+#[test]
+pub fn format_pretty_arranges_match_arms() {
+    test_code_fixture("match");
+}
+
+#[test]
+pub fn should_arrange_incomplete_match_as_call() {
+    for input in ["(match)", "(match x 1 \"a\" \"default\")"] {
+        let exprs = parse_string_for_analysis(input).unwrap();
+        let output = Formatter::new(&exprs).format();
+
+        assert_eq!(output, format!("{input}\n"));
+    }
+}
+
+#[test]
+pub fn should_handle_deeply_nested_match_arms() {
+    let depth = 32;
+
+    let input = format!("{}1{}", "(match x _ ".repeat(depth), ")".repeat(depth));
+    let exprs = parse_string_for_analysis(&input).unwrap();
+    let options = Options {
+        indent_size: 1,
+        ..Default::default()
+    };
+    let output = Formatter::new(&exprs).with_options(options).format();
+
+    // #insight The innermost arm fits on one line.
+    assert!(output.contains(" _ 1\n"));
+}
+
+#[test]
+pub fn format_pretty_aligns_else_if_chains() {
+    test_code_fixture("if-chain");
+}
+
+#[test]
+pub fn format_pretty_collapses_short_ifs() {
+    let options = Options {
+        collapse_short_ifs: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("if-chain", "collapse", Dialect::Code, options);
+}
+
+#[test]
+pub fn should_handle_deeply_nested_short_ifs() {
+    let depth = 32;
+
+    // The nested `if` forms are in the then-position, they cannot be chained.
+    let input = format!("{}1{}", "(if a ".repeat(depth), " 2)".repeat(depth));
+    let exprs = parse_string_for_analysis(&input).unwrap();
+    let options = Options {
+        indent_size: 1,
+        collapse_short_ifs: true,
+        ..Default::default()
+    };
+    let output = Formatter::new(&exprs).with_options(options).format();
+
+    assert!(output.lines().all(|line| line.len() <= 80));
+    assert!(output.contains("(if a (if a (if a 1 2) 2) 2)"));
+}
+
+#[test]
+pub fn format_pretty_arranges_quoted_forms_recursively() {
+    test_code_fixture("quote-nested");
+}
+
 // #think the formatter cannot format vertically, but it should at least handle the inline comment
 // pathological snippet with inline comment.
 #[test]
@@ -98,6 +282,32 @@ pub fn pairs_and_comments_pathological_case() {
     test_code_fixture("pairs-and-comments");
 }
 
+#[test]
+pub fn format_pretty_reflows_comments() {
+    let options = Options {
+        reflow_comments: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("comment-reflow", "reflow", Dialect::Code, options);
+}
+
+#[test]
+pub fn format_pretty_normalizes_comment_markers() {
+    let options = Options {
+        comment_markers: Some(CommentMarkers {
+            top_level: ";;".to_string(),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    test_fixture_with_options("comment-markers", "normalized", Dialect::Code, options);
+}
+
+#[test]
+pub fn format_pretty_keeps_dangling_comments_inside_collections() {
+    test_code_fixture("dangling-comments");
+}
+
 // #todo make this
 // #[test]
 // pub fn should_handle_multiline_text() {
@@ -123,3 +333,132 @@ pub fn should_retain_func_type_annotation() {
 pub fn should_handle_let_with_var_annotation() {
     test_code_fixture("let-ann");
 }
+
+#[test]
+pub fn should_handle_blocks_with_only_comments() {
+    test_code_fixture("commented-stubs");
+}
+
+#[test]
+pub fn should_sort_map_keys_by_annotation() {
+    test_code_fixture("sort-keys");
+}
+
+#[test]
+pub fn should_sort_map_keys_by_priority() {
+    let options = Options {
+        map_key_order: MapKeyOrder::Priority(vec!["value".to_string()]),
+        ..Default::default()
+    };
+    test_fixture_with_options("data-2", "priority", Dialect::Data, options);
+}
+
+#[test]
+pub fn should_stream_to_io_writer() {
+    let exprs = parse_file("fibalike.tan").unwrap();
+    let formatter = Formatter::new(&exprs);
+
+    let mut output = Vec::new();
+    formatter.write_to(&mut output).unwrap();
+    let expected_output = read_file("fibalike.pretty.tan");
+
+    assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+}
+
+#[test]
+pub fn should_handle_deeply_nested_input() {
+    // #insight The depth is bounded by the recursion of the parser.
+    let depth = 500;
+
+    let input = format!("{}[1..3 ;deep\n]{}", "(f ".repeat(depth), ")".repeat(depth));
+    let exprs = parse_string_for_analysis(&input).unwrap();
+    let options = Options {
+        comment_markers: Some(CommentMarkers::default()),
+        ..Default::default()
+    };
+    let output = Formatter::new(&exprs).with_options(options).format();
+
+    // #insight
+    // The lists beyond the maximum arrange depth are formatted flat, with the
+    // same sugar and comment normalization.
+    let expected_output = format!(
+        "{}[1..3 ; deep\n]{}\n",
+        "(f ".repeat(depth),
+        ")".repeat(depth)
+    );
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn should_format_comments_in_any_position() {
+    let input = read_file("comment-positions.tan");
+    let exprs = parse_file("comment-positions.tan").unwrap();
+    let output = Formatter::new(&exprs).with_source(&input).format();
+    let expected_output = read_file("comment-positions.pretty.tan");
+
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn should_reconcile_comments_missing_from_the_exprs() {
+    let input = read_file("comment-positions.tan");
+    let exprs = parse_file("comment-positions.tan").unwrap();
+
+    // Drop the top-level comments, e.g. as a parser that does not retain them.
+    let exprs: Vec<Expr> = exprs
+        .into_iter()
+        .filter(|expr| !matches!(expr.unpack(), Expr::Comment(..)))
+        .collect();
+
+    let output = Formatter::new(&exprs).with_source(&input).format();
+    let expected_output = read_file("comment-positions.pretty.tan");
+
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn format_pretty_aligns_call_arguments_with_the_first_argument() {
+    let options = Options {
+        hanging_alignment: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("hanging", "hanging", Dialect::Code, options);
+}
+
+#[test]
+pub fn format_pretty_hanging_with_kept_line_breaks_is_idempotent() {
+    let options = Options {
+        hanging_alignment: true,
+        keep_line_breaks: true,
+        ..Default::default()
+    };
+    let exprs = parse_file("hanging.tan").unwrap();
+    let output = Formatter::new(&exprs)
+        .with_options(options.clone())
+        .format();
+
+    let exprs = parse_string_for_analysis(&output).unwrap();
+    let output_again = Formatter::new(&exprs).with_options(options).format();
+
+    assert!(output.contains("(make-config\n"));
+    assert_eq!(output, output_again);
+}
+
+#[test]
+pub fn format_pretty_indents_with_tabs_and_aligns_with_spaces() {
+    let options = Options {
+        use_tabs: true,
+        ..Default::default()
+    };
+    test_fixture_with_options("fibalike", "tabs", Dialect::Code, options);
+}
+
+#[test]
+pub fn format_pretty_handles_zero_tab_width() {
+    let options = Options {
+        use_tabs: true,
+        tab_width: 0,
+        ..Default::default()
+    };
+    test_fixture_with_options("fibalike", "tabs", Dialect::Code, options);
+}
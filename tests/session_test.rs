@@ -0,0 +1,48 @@
+use common::read_file;
+use tan_formatting::{
+    session::FormatterSession,
+    types::{Dialect, Options},
+};
+
+mod common;
+
+#[test]
+pub fn session_reuses_unchanged_forms() {
+    let input = read_file("fibalike.tan");
+    let expected_output = read_file("fibalike.pretty.tan");
+
+    let mut session = FormatterSession::new();
+
+    let output = session.format(&input).unwrap();
+    assert_eq!(output, expected_output);
+    assert_eq!(session.reused_count(), 0);
+
+    let output = session.format(&input).unwrap();
+    assert_eq!(output, expected_output);
+    let reused_count = session.reused_count();
+    assert!(reused_count > 0);
+
+    // Edit only the last form.
+    let input = input.replace("(fib 10)", "(fib   20)");
+    let expected_output = expected_output.replace("(fib 10)", "(fib 20)");
+
+    let output = session.format(&input).unwrap();
+    assert_eq!(output, expected_output);
+    assert_eq!(session.reused_count(), reused_count - 1);
+}
+
+#[test]
+pub fn session_rearranges_forms_on_dialect_change() {
+    let input = read_file("data-2.tan");
+
+    let mut session = FormatterSession::new().with_options(Options::for_dialect(Dialect::Data));
+
+    let output = session.format(&input).unwrap();
+    assert_ne!(output, read_file("data-2.pretty.tan"));
+
+    session.set_dialect(Dialect::Data);
+
+    let output = session.format(&input).unwrap();
+    assert_eq!(output, read_file("data-2.pretty.tan"));
+    assert_eq!(session.reused_count(), 0);
+}
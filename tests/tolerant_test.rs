@@ -0,0 +1,24 @@
+use common::read_file;
+use tan_formatting::tolerant::TolerantFormatter;
+
+mod common;
+
+#[test]
+pub fn format_tolerant_copies_unparseable_regions() {
+    let input = read_file("partial.tan");
+    let (output, errors) = TolerantFormatter::new(&input).format();
+    let expected_output = read_file("partial.pretty.tan");
+
+    assert!(!errors.is_empty());
+    assert_eq!(output, expected_output);
+}
+
+#[test]
+pub fn format_tolerant_handles_valid_input() {
+    let input = read_file("fibalike.tan");
+    let (output, errors) = TolerantFormatter::new(&input).format();
+    let expected_output = read_file("fibalike.pretty.tan");
+
+    assert!(errors.is_empty());
+    assert_eq!(output, expected_output);
+}